@@ -1,43 +1,311 @@
-use sled::{Db, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Mutex;
+
+#[cfg(feature = "sled")]
+pub use super::sled_db::{GcStats, SledDB};
+
+/// The backend a trie uses unless it is given another: `SledDB`, or `MemoryDB` when the crate is
+/// built without the `sled` feature.
+#[cfg(feature = "sled")]
+pub type DefaultDB = SledDB;
+#[cfg(not(feature = "sled"))]
+pub type DefaultDB = MemoryDB;
+
+/// Hashed nodes written together, keyed by hash.
+pub type NodeBatch = Vec<([u8; 32], Vec<u8>)>;
 
 pub trait HashDB {
-    type Error: std::fmt::Debug;
+    type Error: std::error::Error + Send + Sync + 'static;
     fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error>;
     fn put(&self, key: [u8; 32], value: Vec<u8>) -> Result<(), Self::Error>;
     fn flush(&self) -> Result<(), Self::Error>;
+
+    /// Store every node in `batch`. Backends that can should do it atomically, so a crash leaves
+    /// either all of them or none; by default they are put one at a time.
+    fn write_batch(&self, batch: NodeBatch) -> Result<(), Self::Error> {
+        for (key, value) in batch {
+            self.put(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Called by `write_commit` once every node below `root` is written. Registries record it as
+    /// the latest root and backends that prune use it to keep the nodes of recent roots alive;
+    /// by default it does nothing.
+    fn commit_root(&self, _root: [u8; 32]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Store the nodes `Trie::commit` staged for `root` and commit the root. Backends that can
+    /// should do both in one atomic write; by default the nodes go first, then `commit_root`.
+    fn write_commit(&self, batch: NodeBatch, root: [u8; 32]) -> Result<(), Self::Error> {
+        self.write_batch(batch)?;
+        self.commit_root(root)
+    }
+}
+
+/// A node store that also keeps the keys behind `SecureTrie`'s hashed paths.
+pub trait PreimageDB: HashDB {
+    fn get_preimage(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn put_preimage(&self, hash: [u8; 32], key: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Store every `(hash, key)` pair. Backends that can should do it atomically; by default
+    /// they are put one at a time.
+    fn put_preimages(&self, preimages: Vec<([u8; 32], Vec<u8>)>) -> Result<(), Self::Error> {
+        for (hash, key) in preimages {
+            self.put_preimage(hash, key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Committed roots kept under a label, so a trie can be reopened at one of them after a restart.
+pub trait RootRegistry: HashDB {
+    /// Record `root` under `label`, replacing the root it had.
+    fn put_root(&self, label: RootLabel, root: [u8; 32]) -> Result<(), Self::Error>;
+    fn get_root(&self, label: &RootLabel) -> Result<Option<[u8; 32]>, Self::Error>;
+    /// Forget `label`. Returns `false` if it had no root.
+    fn delete_root(&self, label: &RootLabel) -> Result<bool, Self::Error>;
+    /// Every entry, block numbers in ascending order first, then names.
+    fn roots(&self) -> Result<Vec<(RootLabel, [u8; 32])>, Self::Error>;
+    /// The root of the most recent commit, whether it was registered under a label or not.
+    fn latest_root(&self) -> Result<Option<[u8; 32]>, Self::Error>;
+
+    /// `write_commit`, also registering `root` under `label`. Backends that can should do it all
+    /// in one atomic write; by default the commit goes first, so the label never names a root
+    /// whose nodes were not written.
+    fn commit_batch(
+        &self,
+        batch: NodeBatch,
+        label: RootLabel,
+        root: [u8; 32],
+    ) -> Result<(), Self::Error> {
+        self.write_commit(batch, root)?;
+        self.put_root(label, root)
+    }
+}
+
+/// What a root is recorded under in a `RootRegistry`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RootLabel {
+    Block(u64),
+    Name(String),
+}
+
+impl From<u64> for RootLabel {
+    fn from(number: u64) -> Self {
+        RootLabel::Block(number)
+    }
+}
+
+impl From<&str> for RootLabel {
+    fn from(name: &str) -> Self {
+        RootLabel::Name(name.to_string())
+    }
+}
+
+impl From<String> for RootLabel {
+    fn from(name: String) -> Self {
+        RootLabel::Name(name)
+    }
+}
+
+impl fmt::Display for RootLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RootLabel::Block(number) => write!(f, "block {}", number),
+            RootLabel::Name(name) => write!(f, "{:?}", name),
+        }
+    }
+}
+
+/// Nodes kept in memory with a reference count each. Putting a node that is already stored
+/// counts it again and `remove` counts it down; a node whose count reaches zero reads as absent
+/// and is dropped by the next `purge`.
+#[derive(Debug, Default)]
+pub struct MemoryDB {
+    nodes: Mutex<HashMap<[u8; 32], Counted>>,
+    preimages: Mutex<HashMap<[u8; 32], Vec<u8>>>,
+    registry: Mutex<BTreeMap<RootLabel, [u8; 32]>>,
+    latest: Mutex<Option<[u8; 32]>>,
 }
 
 #[derive(Debug)]
-pub struct SledDB {
-    tree: Tree,
+struct Counted {
+    value: Vec<u8>,
+    refs: usize,
 }
 
-impl SledDB {
-    pub fn open(path: impl AsRef<std::path::Path>, tree_name: &str) -> Result<Self, sled::Error> {
-        let db: Db = sled::open(path)?;
-        let tree = db.open_tree(tree_name.as_bytes())?;
-        Ok(Self { tree })
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one reference to `key` less. Returns `false` if it had none left.
+    pub fn remove(&self, key: &[u8; 32]) -> bool {
+        match self.nodes.lock().unwrap().get_mut(key) {
+            Some(node) if node.refs > 0 => {
+                node.refs -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop every node nothing refers to any more.
+    pub fn purge(&self) {
+        self.nodes.lock().unwrap().retain(|_, node| node.refs > 0);
+    }
+
+    /// How many times `key` is currently referenced.
+    pub fn refcount(&self, key: &[u8; 32]) -> usize {
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |node| node.refs)
+    }
+
+    /// Number of nodes held, including those waiting for a purge.
+    pub fn len(&self) -> usize {
+        self.nodes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
-impl HashDB for SledDB {
-    type Error = sled::Error;
+impl HashDB for MemoryDB {
+    type Error = Infallible;
 
     fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
-        Ok(self.tree.get(key)?.map(|ivec| ivec.to_vec()))
+        Ok(match self.nodes.lock().unwrap().get(key) {
+            Some(node) if node.refs > 0 => Some(node.value.clone()),
+            _ => None,
+        })
     }
 
     fn put(&self, key: [u8; 32], value: Vec<u8>) -> Result<(), Self::Error> {
-        println!("putting key: {:x?}", key);
-        println!("value: {:x?}", value);
-        // idempotent: same key always same value
-        self.tree.insert(key, value)?;
-
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.entry(key).or_insert(Counted {
+            value: vec![],
+            refs: 0,
+        });
+        // Keyed by hash, so the value only changes for keys that are not node hashes
+        node.value = value;
+        node.refs += 1;
         Ok(())
     }
 
     fn flush(&self) -> Result<(), Self::Error> {
-        self.tree.flush()?; // or flush_async().wait()
         Ok(())
     }
+
+    fn commit_root(&self, root: [u8; 32]) -> Result<(), Self::Error> {
+        *self.latest.lock().unwrap() = Some(root);
+        Ok(())
+    }
+}
+
+impl PreimageDB for MemoryDB {
+    fn get_preimage(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.preimages.lock().unwrap().get(hash).cloned())
+    }
+
+    fn put_preimage(&self, hash: [u8; 32], key: Vec<u8>) -> Result<(), Self::Error> {
+        self.preimages.lock().unwrap().insert(hash, key);
+        Ok(())
+    }
+}
+
+impl RootRegistry for MemoryDB {
+    fn put_root(&self, label: RootLabel, root: [u8; 32]) -> Result<(), Self::Error> {
+        self.registry.lock().unwrap().insert(label, root);
+        Ok(())
+    }
+
+    fn get_root(&self, label: &RootLabel) -> Result<Option<[u8; 32]>, Self::Error> {
+        Ok(self.registry.lock().unwrap().get(label).copied())
+    }
+
+    fn delete_root(&self, label: &RootLabel) -> Result<bool, Self::Error> {
+        Ok(self.registry.lock().unwrap().remove(label).is_some())
+    }
+
+    fn roots(&self) -> Result<Vec<(RootLabel, [u8; 32])>, Self::Error> {
+        let registry = self.registry.lock().unwrap();
+        Ok(registry.iter().map(|(l, r)| (l.clone(), *r)).collect())
+    }
+
+    fn latest_root(&self) -> Result<Option<[u8; 32]>, Self::Error> {
+        Ok(*self.latest.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    #[test]
+    fn memory_db_counts_references() {
+        let db = MemoryDB::new();
+        let key = [1; 32];
+
+        db.put(key, b"node".to_vec()).unwrap();
+        db.put(key, b"node".to_vec()).unwrap();
+        assert_eq!(db.refcount(&key), 2);
+
+        assert!(db.remove(&key));
+        assert_eq!(db.get(&key).unwrap(), Some(b"node".to_vec()));
+
+        // At zero the node reads as gone, but stays until purged
+        assert!(db.remove(&key));
+        assert!(!db.remove(&key));
+        assert_eq!(db.get(&key).unwrap(), None);
+        assert_eq!(db.len(), 1);
+
+        // Put back before the purge, it is counted from zero again
+        db.put(key, b"node".to_vec()).unwrap();
+        db.purge();
+        assert_eq!(db.refcount(&key), 1);
+
+        db.remove(&key);
+        db.purge();
+        assert!(db.is_empty());
+    }
+
+    pub(crate) fn check_registry(db: &impl RootRegistry) {
+        assert_eq!(db.latest_root().unwrap(), None);
+
+        db.put_root(RootLabel::Block(10), [10; 32]).unwrap();
+        db.put_root("tip".into(), [1; 32]).unwrap();
+        db.put_root(RootLabel::Block(9), [9; 32]).unwrap();
+        assert_eq!(
+            db.roots().unwrap(),
+            vec![
+                (RootLabel::Block(9), [9; 32]),
+                (RootLabel::Block(10), [10; 32]),
+                ("tip".into(), [1; 32]),
+            ]
+        );
+        // Only commits move the latest root, registered or not
+        assert_eq!(db.latest_root().unwrap(), None);
+        db.commit_root([3; 32]).unwrap();
+        assert_eq!(db.latest_root().unwrap(), Some([3; 32]));
+
+        db.put_root("tip".into(), [2; 32]).unwrap();
+        assert_eq!(db.get_root(&"tip".into()).unwrap(), Some([2; 32]));
+        assert!(db.delete_root(&"tip".into()).unwrap());
+        assert!(!db.delete_root(&"tip".into()).unwrap());
+        assert_eq!(db.get_root(&"tip".into()).unwrap(), None);
+        assert_eq!(db.roots().unwrap().len(), 2);
+        assert_eq!(db.latest_root().unwrap(), Some([3; 32]));
+    }
+
+    #[test]
+    fn registry_lists_latest_and_deletes() {
+        check_registry(&MemoryDB::new());
+    }
 }
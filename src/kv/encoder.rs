@@ -65,13 +65,11 @@ fn length_to_minimal_bytes(len: usize) -> Vec<u8> {
 }
 
 fn bytes_to_integer(data: &[u8]) -> Result<usize, RlpError> {
-    if data.len() == 0 {
+    if data.is_empty() {
         return Ok(0);
     }
 
-    println!("data: {:?}", data);
-
-    if data.len() >= 1 && data[0] == 0 {
+    if data[0] == 0 {
         return Err(RlpError::InvalidLengthEncoding);
     }
 
@@ -234,10 +232,6 @@ pub fn encode_rlp(data: &RlpData) -> Vec<u8> {
                 payload.extend(encode_rlp(item));
             }
 
-            println!("Payload after encoding: {:x?}", payload);
-
-            println!("Length of payload: {}", payload.len());
-
             // Then prepend the length
             let mut encoded = encode_length(payload.len(), 0xc0);
             encoded.extend(payload);
@@ -310,7 +304,6 @@ impl RlpData {
 }
 
 #[cfg(test)]
-
 mod unit_tests {
     use super::*;
 
@@ -1,3 +1,5 @@
 pub mod db;
 pub mod encoder;
+#[cfg(feature = "sled")]
+pub(crate) mod sled_db;
 pub mod storage;
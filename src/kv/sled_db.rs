@@ -0,0 +1,916 @@
+use crate::kv::db::{HashDB, NodeBatch, PreimageDB, RootLabel, RootRegistry};
+use crate::kv::storage::{EMPTY_ROOT, child_hashes, decode_node, hashed_children};
+use crate::trie::TrieError;
+use sha3::{Digest, Keccak256};
+use sled::transaction::{
+    TransactionError, Transactional, TransactionalTree, UnabortableTransactionError,
+};
+use sled::{Db, IVec, Tree};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::Mutex;
+
+impl RootLabel {
+    // Tagged so that blocks sort before names and in numeric order
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            RootLabel::Block(number) => [&[0][..], &number.to_be_bytes()].concat(),
+            RootLabel::Name(name) => [&[1][..], name.as_bytes()].concat(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, sled::Error> {
+        match bytes.split_first() {
+            Some((0, number)) => Ok(RootLabel::Block(u64::from_be_bytes(fixed(
+                number,
+                "block number",
+            )?))),
+            Some((1, name)) => match String::from_utf8(name.to_vec()) {
+                Ok(name) => Ok(RootLabel::Name(name)),
+                Err(_) => Err(invalid_data("root label name is not UTF-8".into())),
+            },
+            _ => Err(invalid_data(format!("unknown root label {bytes:?}"))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SledDB {
+    tree: Tree,
+    preimages: Tree, // keccak(key) -> key, kept apart from the nodes
+    registry: Tree,  // label -> root hash, and LATEST -> root of the last commit
+    pruning: Option<Pruning>,
+}
+
+/// Registry key of the latest root. Labels start with tag 0 or 1, so it sorts after all of them.
+const LATEST: &[u8] = &[0xff];
+
+/// Node tree key under which stores written before the registry kept the latest root.
+fn legacy_root_key() -> [u8; 32] {
+    Keccak256::digest(b"__ROOT__").into()
+}
+
+/// Reference counts for pruning: every stored node is counted once per stored parent that
+/// references it by hash, and once per retained root it is.
+#[derive(Debug)]
+struct Pruning {
+    refs: Tree,     // hash -> u64 count
+    roots: Tree,    // u64 commit number -> root hash, oldest first
+    released: Tree, // root hash -> u64 references still to drop, queued by the commit dropping them
+    retain: usize,
+    // Storing a node and counting its children have to happen together
+    lock: Mutex<()>,
+}
+
+/// A committed root's place in the pruning window.
+struct Window {
+    next: [u8; 8],                  // the commit number it is retained under
+    evicted: Vec<(IVec, [u8; 32])>, // the retained roots that make room for it, oldest first
+}
+
+impl SledDB {
+    pub fn open(path: impl AsRef<std::path::Path>, tree_name: &str) -> Result<Self, sled::Error> {
+        Self::open_trees(&sled::open(path)?, tree_name)
+    }
+
+    fn open_trees(db: &Db, tree_name: &str) -> Result<Self, sled::Error> {
+        let tree = db.open_tree(tree_name.as_bytes())?;
+        let preimages = db.open_tree(format!("{tree_name}/preimages").as_bytes())?;
+        let registry = db.open_tree(format!("{tree_name}/registry").as_bytes())?;
+        let db = Self {
+            tree,
+            preimages,
+            registry,
+            pruning: None,
+        };
+        db.migrate_root_pointer()?;
+        Ok(db)
+    }
+
+    /// Move the root pointer an older store keeps among its nodes into the registry as the
+    /// latest root, unless a commit since has recorded one. It is taken out of the node tree,
+    /// where garbage collection would sweep it.
+    fn migrate_root_pointer(&self) -> Result<(), sled::Error> {
+        let key = legacy_root_key();
+        if !self.tree.contains_key(key)? {
+            return Ok(());
+        }
+        (&self.tree, &self.registry)
+            .transaction(|(tree, registry)| {
+                let Some(root) = tree.remove(&key[..])? else {
+                    return Ok(());
+                };
+                if registry.get(LATEST)?.is_none() {
+                    let root: [u8; 32] = fixed(&root, "legacy root pointer")?;
+                    registry.insert(LATEST, &root[..])?;
+                }
+                Ok(())
+            })
+            .map_err(storage_error)
+    }
+
+    /// Like `open`, but deletes the nodes that only the roots older than the last `retain`
+    /// commits refer to. Opening an old root after it has been pruned fails with a missing node.
+    /// `retain` has to be at least one, the latest root is always kept.
+    ///
+    /// Counting starts with the first commit made this way, so enable it on a fresh store:
+    /// nodes written without it are not counted, and pruning could remove them from under
+    /// older parents. Roots in the registry are kept until their label is deleted, provided
+    /// they are registered before they fall out of the window.
+    pub fn open_pruned(
+        path: impl AsRef<std::path::Path>,
+        tree_name: &str,
+        retain: usize,
+    ) -> Result<Self, sled::Error> {
+        if retain == 0 {
+            let reason = "pruning has to retain at least the latest root";
+            return Err(sled::Error::Unsupported(reason.into()));
+        }
+        let db: Db = sled::open(path)?;
+        let pruning = Pruning {
+            refs: db.open_tree(format!("{tree_name}/refs").as_bytes())?,
+            roots: db.open_tree(format!("{tree_name}/roots").as_bytes())?,
+            released: db.open_tree(format!("{tree_name}/released").as_bytes())?,
+            retain,
+            lock: Mutex::new(()),
+        };
+        let db = Self {
+            pruning: Some(pruning),
+            ..Self::open_trees(&db, tree_name)?
+        };
+        // Finish the releases a crash cut short
+        db.release_queued()?;
+        Ok(db)
+    }
+}
+
+impl HashDB for SledDB {
+    type Error = sled::Error;
+
+    fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.tree.get(key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn put(&self, key: [u8; 32], value: Vec<u8>) -> Result<(), Self::Error> {
+        if self.pruning.is_some() {
+            // The node's children have to be counted along with it
+            return self.write_atomically(&[(key, value)], None, None);
+        }
+        // idempotent: same key always same value
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.tree.flush()?; // or flush_async().wait()
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: NodeBatch) -> Result<(), Self::Error> {
+        self.write_atomically(&batch, None, None)
+    }
+
+    fn commit_root(&self, root: [u8; 32]) -> Result<(), Self::Error> {
+        self.write_atomically(&[], Some(root), None)
+    }
+
+    fn write_commit(&self, batch: NodeBatch, root: [u8; 32]) -> Result<(), Self::Error> {
+        self.write_atomically(&batch, Some(root), None)
+    }
+}
+
+/// What `SledDB::collect_garbage` kept and removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    pub live_entries: usize,
+    pub reclaimed_entries: usize,
+    /// Keys and values of the removed entries
+    pub reclaimed_bytes: u64,
+}
+
+impl SledDB {
+    /// Delete every node that none of `live_roots` reaches, for stores that were not opened with
+    /// pruning or to compact one after the fact. Run it while nothing else writes to the store.
+    ///
+    /// Every reachable node has to be present and decodable; otherwise nothing is deleted and
+    /// the error says which node is missing or broken. The latest root and the roots in the
+    /// registry are kept too, and with pruning so are the roots it retains; its reference counts
+    /// are then rebuilt from what is left.
+    pub fn collect_garbage(&self, live_roots: &[[u8; 32]]) -> Result<GcStats, TrieError> {
+        let _guard = self.pruning.as_ref().map(|p| p.lock.lock().unwrap());
+
+        let mut roots = live_roots.to_vec();
+        roots.extend(self.latest_root().map_err(TrieError::db)?);
+        // Counted the way pruning counts them: once per registered or retained root, once per
+        // stored parent
+        let mut refs: HashMap<[u8; 32], u64> = HashMap::new();
+        let mut count_root = |root: [u8; 32]| {
+            *refs.entry(root).or_default() += 1;
+            roots.push(root);
+        };
+        for (_, root) in self.roots().map_err(TrieError::db)? {
+            count_root(root);
+        }
+        if let Some(pruning) = &self.pruning {
+            for entry in pruning.roots.iter() {
+                let (_, root) = entry.map_err(TrieError::db)?;
+                count_root(fixed(&root, "retained root").map_err(TrieError::db)?);
+            }
+        }
+
+        // Mark
+        let mut live = HashSet::new();
+        let mut stack: Vec<([u8; 32], Vec<u8>)> = roots.into_iter().map(|r| (r, vec![])).collect();
+        while let Some((hash, path)) = stack.pop() {
+            if hash == EMPTY_ROOT || !live.insert(hash) {
+                continue;
+            }
+            let encoded = self.tree.get(hash).map_err(TrieError::db)?.ok_or_else(|| {
+                TrieError::MissingNode {
+                    hash,
+                    path: path.clone(),
+                }
+            })?;
+            for (nibbles, child) in child_hashes(&decode_node(&encoded)?) {
+                *refs.entry(child).or_default() += 1;
+                stack.push((child, [path.as_slice(), &nibbles].concat()));
+            }
+        }
+
+        // Sweep
+        let mut stats = GcStats {
+            live_entries: live.len(),
+            ..Default::default()
+        };
+        for entry in self.tree.iter() {
+            let (key, value) = entry.map_err(TrieError::db)?;
+            let Ok(hash) = <[u8; 32]>::try_from(key.as_ref()) else {
+                continue;
+            };
+            if live.contains(&hash) {
+                continue;
+            }
+            self.tree.remove(key).map_err(TrieError::db)?;
+            stats.reclaimed_entries += 1;
+            stats.reclaimed_bytes += (hash.len() + value.len()) as u64;
+        }
+
+        if let Some(pruning) = &self.pruning {
+            pruning.refs.clear().map_err(TrieError::db)?;
+            for (hash, count) in refs {
+                if live.contains(&hash) {
+                    pruning
+                        .refs
+                        .insert(hash, &count.to_be_bytes())
+                        .map_err(TrieError::db)?;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Store `nodes`, commit a root as the latest one and register a root, the last two only if
+    /// given, all in one transaction: after a crash either every write is there or none is.
+    /// With pruning, the references the new entries add are counted and a committed root takes
+    /// its place among the retained ones in the same transaction, which also queues the roots it
+    /// evicts or replaces for release.
+    fn write_atomically(
+        &self,
+        nodes: &[([u8; 32], Vec<u8>)],
+        latest: Option<[u8; 32]>,
+        registered: Option<(&RootLabel, [u8; 32])>,
+    ) -> Result<(), sled::Error> {
+        let _guard = self.pruning.as_ref().map(|p| p.lock.lock().unwrap());
+        let mut trees = vec![&self.tree, &self.registry];
+        if let Some(pruning) = &self.pruning {
+            trees.extend([&pruning.refs, &pruning.roots, &pruning.released]);
+        }
+        let label = registered.map(|(label, _)| label.to_bytes());
+        // Read ahead of the transaction, the lock keeps it from changing until the end
+        let window = match (&self.pruning, latest) {
+            (Some(pruning), Some(_)) => Some(pruning.next_retained()?),
+            _ => None,
+        };
+
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                let (tree, registry) = (&trees[0], &trees[1]);
+                let (refs, roots, released) = (trees.get(2), trees.get(3), trees.get(4));
+                for (key, value) in nodes {
+                    #[cfg(test)]
+                    tests::inject_fault()?;
+                    // A node stored before keeps the counts it added for its children
+                    if let Some(refs) = refs
+                        && tree.get(key)?.is_none()
+                    {
+                        for child in hashed_children(value) {
+                            count_ref(refs, &child)?;
+                        }
+                    }
+                    tree.insert(&key[..], value.as_slice())?;
+                }
+
+                if let Some(root) = latest {
+                    #[cfg(test)]
+                    tests::inject_fault()?;
+                    registry.insert(LATEST, &root[..])?;
+                }
+                if let (Some(refs), Some(roots), Some(released), Some(root), Some(window)) =
+                    (refs, roots, released, latest, &window)
+                {
+                    roots.insert(&window.next[..], &root[..])?;
+                    count_ref(refs, &root)?;
+                    for (number, old) in &window.evicted {
+                        roots.remove(&number[..])?;
+                        count_ref(released, old)?;
+                    }
+                }
+
+                let (Some(label), Some((_, root))) = (&label, registered) else {
+                    return Ok(());
+                };
+                let old = registry.insert(label.as_slice(), &root[..])?;
+                // Registered roots count as references, so pruning keeps them until deleted
+                if let (Some(refs), Some(released)) = (refs, released) {
+                    count_ref(refs, &root)?;
+                    if let Some(old) = old {
+                        count_ref(released, &fixed(&old, "registered root")?)?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(storage_error)?;
+
+        self.release_queued()
+    }
+
+    /// Drop the references queued for release, deleting every node that is left without any.
+    /// Each root is released in a transaction that also takes it off the queue, so a release a
+    /// crash cut short is done over in full by the next one. Call it with the pruning lock held.
+    fn release_queued(&self) -> Result<(), sled::Error> {
+        let Some(pruning) = &self.pruning else {
+            return Ok(());
+        };
+        for entry in pruning.released.iter() {
+            let (root, times) = entry?;
+            let root: [u8; 32] = fixed(&root, "released root")?;
+            let times = read_count(Some(&times))?;
+            (&self.tree, &pruning.refs, &pruning.released)
+                .transaction(|(tree, refs, released)| {
+                    released.remove(&root[..])?;
+                    let mut stack = vec![(root, times)];
+                    while let Some((hash, times)) = stack.pop() {
+                        #[cfg(test)]
+                        tests::inject_fault()?;
+                        if add_ref(refs, &hash, -(times as i64))? > 0 {
+                            continue;
+                        }
+                        refs.remove(&hash[..])?;
+                        if let Some(node) = tree.remove(&hash[..])? {
+                            stack.extend(hashed_children(&node).into_iter().map(|h| (h, 1)));
+                        }
+                    }
+                    Ok(())
+                })
+                .map_err(storage_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pruning {
+    /// Where the next committed root goes among the retained ones.
+    fn next_retained(&self) -> Result<Window, sled::Error> {
+        let next = match self.roots.last()? {
+            Some((number, _)) => u64::from_be_bytes(fixed(&number, "commit number")?) + 1,
+            None => 0,
+        };
+        let evicted = (self.roots.len() + 1).saturating_sub(self.retain);
+        let evicted = self.roots.iter().take(evicted).map(|entry| {
+            let (number, root) = entry?;
+            Ok((number, fixed(&root, "retained root")?))
+        });
+        Ok(Window {
+            next: next.to_be_bytes(),
+            evicted: evicted.collect::<Result<_, sled::Error>>()?,
+        })
+    }
+}
+
+impl PreimageDB for SledDB {
+    fn get_preimage(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.preimages.get(hash)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn put_preimage(&self, hash: [u8; 32], key: Vec<u8>) -> Result<(), Self::Error> {
+        self.preimages.insert(hash, key)?;
+        Ok(())
+    }
+
+    fn put_preimages(&self, preimages: Vec<([u8; 32], Vec<u8>)>) -> Result<(), Self::Error> {
+        let mut batch = sled::Batch::default();
+        for (hash, key) in preimages {
+            batch.insert(&hash[..], key);
+        }
+        self.preimages.apply_batch(batch)
+    }
+}
+
+impl RootRegistry for SledDB {
+    fn put_root(&self, label: RootLabel, root: [u8; 32]) -> Result<(), Self::Error> {
+        self.write_atomically(&[], None, Some((&label, root)))
+    }
+
+    fn get_root(&self, label: &RootLabel) -> Result<Option<[u8; 32]>, Self::Error> {
+        let entry = self.registry.get(label.to_bytes())?;
+        entry
+            .map(|value| fixed(&value, "registered root"))
+            .transpose()
+    }
+
+    fn delete_root(&self, label: &RootLabel) -> Result<bool, Self::Error> {
+        let Some(pruning) = &self.pruning else {
+            return Ok(self.registry.remove(label.to_bytes())?.is_some());
+        };
+        let _guard = pruning.lock.lock().unwrap();
+        // The root is queued for release in the transaction that deletes its label
+        let deleted = (&self.registry, &pruning.released)
+            .transaction(|(registry, released)| {
+                let Some(root) = registry.remove(label.to_bytes())? else {
+                    return Ok(false);
+                };
+                count_ref(released, &fixed(&root, "registered root")?)?;
+                Ok(true)
+            })
+            .map_err(storage_error)?;
+        self.release_queued()?;
+        Ok(deleted)
+    }
+
+    fn roots(&self) -> Result<Vec<(RootLabel, [u8; 32])>, Self::Error> {
+        self.registry
+            .range(..LATEST)
+            .map(|entry| {
+                let (label, value) = entry?;
+                let root = fixed(&value, "registered root")?;
+                Ok((RootLabel::from_bytes(&label)?, root))
+            })
+            .collect()
+    }
+
+    fn commit_batch(
+        &self,
+        batch: NodeBatch,
+        label: RootLabel,
+        root: [u8; 32],
+    ) -> Result<(), Self::Error> {
+        self.write_atomically(&batch, Some(root), Some((&label, root)))
+    }
+
+    fn latest_root(&self) -> Result<Option<[u8; 32]>, Self::Error> {
+        let entry = self.registry.get(LATEST)?;
+        entry.map(|value| fixed(&value, "latest root")).transpose()
+    }
+}
+
+/// Add one reference to `hash` inside a transaction.
+fn count_ref(refs: &TransactionalTree, hash: &[u8; 32]) -> Result<(), UnabortableTransactionError> {
+    add_ref(refs, hash, 1).map(drop)
+}
+
+/// Change the count of `hash` by `delta` inside a transaction and return the new count. Counts
+/// stop at zero.
+fn add_ref(
+    counts: &TransactionalTree,
+    hash: &[u8; 32],
+    delta: i64,
+) -> Result<u64, UnabortableTransactionError> {
+    let count = read_count(counts.get(hash)?.as_deref())?.saturating_add_signed(delta);
+    counts.insert(&hash[..], &count.to_be_bytes()[..])?;
+    Ok(count)
+}
+
+fn read_count(bytes: Option<&[u8]>) -> Result<u64, sled::Error> {
+    bytes.map_or(Ok(0), |b| {
+        fixed(b, "reference count").map(u64::from_be_bytes)
+    })
+}
+
+/// A fixed-width value read back from one of the bookkeeping trees.
+fn fixed<const N: usize>(bytes: &[u8], what: &str) -> Result<[u8; N], sled::Error> {
+    bytes
+        .try_into()
+        .map_err(|_| invalid_data(format!("malformed {what}: {} bytes", bytes.len())))
+}
+
+fn invalid_data(reason: String) -> sled::Error {
+    sled::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, reason))
+}
+
+/// Our transactions never abort, so they can only fail in the store.
+fn storage_error(err: TransactionError<Infallible>) -> sled::Error {
+    match err {
+        TransactionError::Storage(err) => err,
+        TransactionError::Abort(never) => match never {},
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::kv::db::tests::check_registry;
+    use crate::trie::{Trie, TrieError};
+    use rand::random;
+    use sled::transaction::ConflictableTransactionError;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// How many more nodes a transaction may write before it fails like a crash would.
+        static WRITES_BEFORE_FAULT: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    pub(super) fn inject_fault<E>() -> Result<(), ConflictableTransactionError<E>> {
+        match WRITES_BEFORE_FAULT.get() {
+            Some(0) => {
+                let fault = std::io::Error::other("injected fault");
+                Err(ConflictableTransactionError::Storage(fault.into()))
+            }
+            left => {
+                WRITES_BEFORE_FAULT.set(left.map(|n| n - 1));
+                Ok(())
+            }
+        }
+    }
+
+    /// A directory of its own for a sled store, removed again when dropped.
+    pub(crate) struct TempStore(std::path::PathBuf);
+
+    impl TempStore {
+        pub(crate) fn new(name: &str) -> Self {
+            let name = format!("{name}-{:016x}", rand::random::<u64>());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl AsRef<std::path::Path> for TempStore {
+        fn as_ref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Open a store that was just dropped. Sled gives up its file lock from a background thread,
+    /// so the first tries can still find it held.
+    pub(crate) fn reopen<T, E: std::fmt::Debug>(open: impl Fn() -> Result<T, E>) -> T {
+        for _ in 0..100 {
+            if let Ok(opened) = open() {
+                return opened;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        open().unwrap()
+    }
+
+    #[test]
+    fn registry_lists_latest_and_deletes() {
+        let path = TempStore::new("mpt-registry");
+        check_registry(&SledDB::open(&path, "mpt").unwrap());
+    }
+
+    #[test]
+    fn registry_reports_corrupt_entries() {
+        let path = TempStore::new("mpt-registry-corrupt");
+        let db = SledDB::open(&path, "mpt").unwrap();
+        let malformed = |result| matches!(result, Err(sled::Error::Io(_)));
+
+        db.registry.insert([0, 1, 2], &[1; 32]).unwrap();
+        assert!(malformed(db.roots().map(drop)));
+        db.registry.clear().unwrap();
+        db.registry.insert([1, 0xc3], &[1; 32]).unwrap();
+        assert!(malformed(db.roots().map(drop)));
+        db.registry.clear().unwrap();
+        db.registry.insert([7], &[1; 32]).unwrap();
+        assert!(malformed(db.roots().map(drop)));
+
+        db.registry
+            .insert(RootLabel::Block(1).to_bytes(), vec![1; 40])
+            .unwrap();
+        assert!(malformed(db.get_root(&RootLabel::Block(1)).map(drop)));
+        db.registry.insert(LATEST, &[1; 8]).unwrap();
+        assert!(malformed(db.latest_root().map(drop)));
+    }
+
+    #[test]
+    fn legacy_root_pointers_are_migrated() {
+        let path = TempStore::new("mpt-legacy-root");
+        let mut trie = Trie::with_db(&path, "mpt").unwrap();
+        let keys: Vec<[u8; 32]> = (0..50).map(|_| random()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root = trie.commit().unwrap().canonicalize_root();
+
+        // Lay the store out as before the registry: the latest root is only in the node tree
+        let db = trie.into_db().unwrap();
+        db.registry.remove(LATEST).unwrap();
+        db.tree.insert(legacy_root_key(), &root).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let trie = reopen(|| Trie::with_db(&path, "mpt"));
+        assert_eq!(trie.root_hash(), root);
+        assert_eq!(trie.iter().map(Result::unwrap).count(), 50);
+        let db = trie.into_db().unwrap();
+        assert_eq!(db.latest_root().unwrap(), Some(root));
+        assert!(!db.tree.contains_key(legacy_root_key()).unwrap());
+
+        // A root committed since the pointer was written wins over it
+        db.tree.insert(legacy_root_key(), &[1; 32]).unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let db = reopen(|| SledDB::open(&path, "mpt"));
+        assert_eq!(db.latest_root().unwrap(), Some(root));
+        assert!(!db.tree.contains_key(legacy_root_key()).unwrap());
+    }
+
+    #[test]
+    fn checked_in_store_reopens_at_its_root() {
+        // The store in the repository was written before the registry. Open a copy, so it stays
+        // as it was
+        let path = TempStore::new("mpt-checked-in");
+        let store = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("db");
+        std::fs::create_dir_all(&path).unwrap();
+        for file in std::fs::read_dir(&store).unwrap() {
+            let file = file.unwrap();
+            std::fs::copy(file.path(), path.as_ref().join(file.file_name())).unwrap();
+        }
+
+        let trie = Trie::with_db(&path, "mpt").unwrap();
+        assert_eq!(
+            trie.get(b"523456zyxwvutsrqponmlkjihgfedcba").unwrap(),
+            Some(b"val3".to_vec())
+        );
+        assert_eq!(trie.iter().map(Result::unwrap).count(), 5);
+        drop(trie);
+
+        let trie = reopen(|| Trie::with_db(&path, "complex"));
+        let values: Vec<_> = trie.iter().map(|entry| entry.unwrap().1).collect();
+        assert_eq!(values, vec![b"hello".to_vec(); 5]);
+    }
+
+    #[test]
+    fn interrupted_commits_leave_the_old_root() {
+        let path = TempStore::new("mpt-atomic-commit");
+        let mut trie = Trie::with_db(&path, "mpt").unwrap();
+        let keys: Vec<[u8; 32]> = (0..100).map(|_| random()).collect();
+        for key in &keys[..50] {
+            trie.set(key, key).unwrap();
+        }
+        let old = trie.commit_as(1).unwrap().canonicalize_root();
+        for key in &keys[50..] {
+            trie.set(key, key).unwrap();
+        }
+        let new = trie.root_hash();
+        let stored = trie.db().unwrap().tree.len();
+
+        // However far the batch got, none of it is stored and the registry still has the old root
+        for writes in [0, 1, 20, 40] {
+            WRITES_BEFORE_FAULT.set(Some(writes));
+            assert!(matches!(trie.commit_as(2), Err(TrieError::Db(_))));
+            assert!(matches!(trie.commit(), Err(TrieError::Db(_))));
+            WRITES_BEFORE_FAULT.set(None);
+
+            let db = trie.db().unwrap();
+            assert_eq!(db.tree.len(), stored);
+            assert_eq!(db.latest_root().unwrap(), Some(old));
+        }
+        assert_eq!(trie.commit_as(2).unwrap().canonicalize_root(), new);
+        drop(trie);
+
+        let trie = reopen(|| Trie::with_db_at(&path, "mpt", 1));
+        assert_eq!(trie.iter().count(), 50);
+        assert_eq!(trie.get(keys[49]).unwrap(), Some(keys[49].to_vec()));
+        drop(trie);
+
+        let trie = reopen(|| Trie::with_db(&path, "mpt"));
+        assert_eq!(trie.root_hash(), new);
+        assert_eq!(trie.iter().map(Result::unwrap).count(), 100);
+    }
+
+    /// Every node reachable from `roots`.
+    fn reachable(db: &SledDB, roots: &[[u8; 32]]) -> std::collections::HashSet<[u8; 32]> {
+        let mut seen = std::collections::HashSet::new();
+        let mut stack = roots.to_vec();
+        while let Some(hash) = stack.pop() {
+            if seen.insert(hash) {
+                stack.extend(hashed_children(&db.get(&hash).unwrap().unwrap()));
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn pruning_keeps_only_retained_roots() {
+        let path = TempStore::new("mpt-pruning");
+        let mut trie = Trie::from_db(SledDB::open_pruned(&path, "mpt", 2).unwrap());
+        let keys: Vec<[u8; 32]> = (0..200).map(|_| random()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+
+        let mut roots = vec![trie.commit().unwrap().canonicalize_root()];
+        for round in 0..5u8 {
+            for key in &keys[round as usize * 10..][..10] {
+                trie.set(key, [round; 32]).unwrap();
+            }
+            trie.delete(keys[100 + round as usize]).unwrap();
+            roots.push(trie.commit().unwrap().canonicalize_root());
+        }
+        let db = trie.into_db().unwrap();
+
+        // Only the nodes of the last two roots are left
+        let live = reachable(&db, &roots[4..]);
+        assert_eq!(db.tree.len(), live.len());
+
+        let latest = Trie::open(db, roots[5]);
+        assert_eq!(latest.iter().count(), 195);
+        assert_eq!(latest.get(keys[45]).unwrap(), Some(vec![4; 32]));
+        let db = latest.into_db().unwrap();
+
+        let previous = Trie::open(db, roots[4]);
+        assert_eq!(previous.get(keys[45]).unwrap(), Some(keys[45].to_vec()));
+        let db = previous.into_db().unwrap();
+
+        let pruned = Trie::open(db, roots[0]);
+        assert!(matches!(
+            pruned.get(keys[0]),
+            Err(TrieError::MissingNode { .. })
+        ));
+
+        // Pruning left nothing to collect, and its counts match the ones rebuilt from scratch
+        let db = pruned.into_db().unwrap();
+        let counts = |db: &SledDB| -> Vec<_> {
+            let refs = &db.pruning.as_ref().unwrap().refs;
+            refs.iter().map(Result::unwrap).collect()
+        };
+        let before = counts(&db);
+        assert_eq!(db.collect_garbage(&[]).unwrap().reclaimed_entries, 0);
+        assert_eq!(counts(&db), before);
+    }
+
+    #[test]
+    fn interrupted_commits_leave_the_pruning_window() {
+        let path = TempStore::new("mpt-pruning-atomic");
+        let mut trie = Trie::from_db(SledDB::open_pruned(&path, "mpt", 1).unwrap());
+        let keys: Vec<[u8; 32]> = (0..100).map(|_| random()).collect();
+        for key in &keys[..50] {
+            trie.set(key, key).unwrap();
+        }
+        let old = trie.commit().unwrap().canonicalize_root();
+        for key in &keys[50..] {
+            trie.set(key, key).unwrap();
+        }
+        let new = trie.root_hash();
+        let contents = |db: &SledDB| -> Vec<Vec<_>> {
+            let pruning = db.pruning.as_ref().unwrap();
+            [&db.tree, &pruning.refs, &pruning.roots, &pruning.released]
+                .into_iter()
+                .map(|tree| tree.iter().map(Result::unwrap).collect())
+                .collect()
+        };
+        let before = contents(trie.db().unwrap());
+
+        // Failing at any write, down to the one that commits the root, changes nothing
+        for writes in 0.. {
+            WRITES_BEFORE_FAULT.set(Some(writes));
+            let result = trie.commit();
+            WRITES_BEFORE_FAULT.set(None);
+            assert!(matches!(result, Err(TrieError::Db(_))));
+
+            let db = trie.db().unwrap();
+            if db.latest_root().unwrap() == Some(new) {
+                break;
+            }
+            assert_eq!(contents(db), before);
+            assert_eq!(db.latest_root().unwrap(), Some(old));
+        }
+
+        // The root is committed and the old one is queued, but releasing it failed. However far
+        // a release gets, it leaves the old root's nodes in place and the root in the queue
+        let db = trie.into_db().unwrap();
+        let queued = contents(&db);
+        assert_eq!(db.pruning.as_ref().unwrap().released.len(), 1);
+        assert!(db.tree.len() > reachable(&db, &[new]).len());
+        for writes in [0, 1, 5] {
+            WRITES_BEFORE_FAULT.set(Some(writes));
+            assert!(db.release_queued().is_err());
+            WRITES_BEFORE_FAULT.set(None);
+            assert_eq!(contents(&db), queued);
+        }
+        db.flush().unwrap();
+        drop(db);
+
+        // Opening the store again finishes the release
+        let db = reopen(|| SledDB::open_pruned(&path, "mpt", 1));
+        assert!(db.pruning.as_ref().unwrap().released.is_empty());
+        assert_eq!(db.latest_root().unwrap(), Some(new));
+        assert_eq!(db.tree.len(), reachable(&db, &[new]).len());
+    }
+
+    #[test]
+    fn pruning_keeps_registered_roots() {
+        let path = TempStore::new("mpt-pruning-registry");
+        let mut trie = Trie::from_db(SledDB::open_pruned(&path, "mpt", 1).unwrap());
+        let keys: Vec<[u8; 32]> = (0..50).map(|_| random()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let kept = trie.commit_as("snapshot").unwrap().canonicalize_root();
+        for round in 0..3u8 {
+            trie.set(keys[round as usize], [round; 32]).unwrap();
+            trie.commit().unwrap();
+        }
+        let latest = trie.root_hash();
+        let db = trie.into_db().unwrap();
+        assert_eq!(db.tree.len(), reachable(&db, &[kept, latest]).len());
+
+        let snapshot = Trie::open_at(db, "snapshot").unwrap();
+        assert_eq!(snapshot.iter().count(), 50);
+        assert_eq!(snapshot.get(keys[0]).unwrap(), Some(keys[0].to_vec()));
+
+        // Once its label is gone, the snapshot is pruned like any other old root
+        let db = snapshot.into_db().unwrap();
+        assert!(db.delete_root(&"snapshot".into()).unwrap());
+        assert_eq!(db.tree.len(), reachable(&db, &[latest]).len());
+    }
+
+    #[test]
+    fn pruning_reports_bad_bookkeeping() {
+        let path = TempStore::new("mpt-pruning-bad");
+        assert!(matches!(
+            SledDB::open_pruned(&path, "mpt", 0),
+            Err(sled::Error::Unsupported(_))
+        ));
+
+        let db = SledDB::open_pruned(&path, "mpt", 1).unwrap();
+        let pruning = db.pruning.as_ref().unwrap();
+        pruning.refs.insert([1; 32], &[0; 3]).unwrap();
+        pruning
+            .released
+            .insert([1; 32], &1u64.to_be_bytes())
+            .unwrap();
+        assert!(matches!(db.release_queued(), Err(sled::Error::Io(_))));
+        assert_eq!(pruning.refs.get([1; 32]).unwrap().unwrap(), [0; 3]);
+        assert_eq!(pruning.released.len(), 1);
+        pruning.released.clear().unwrap();
+
+        pruning.roots.insert([0; 3], &[2; 32]).unwrap();
+        assert!(matches!(db.commit_root([2; 32]), Err(sled::Error::Io(_))));
+    }
+
+    #[test]
+    fn garbage_collection_keeps_live_roots() {
+        let path = TempStore::new("mpt-gc");
+        let mut trie = Trie::from_db(SledDB::open(&path, "mpt").unwrap());
+        let keys: Vec<[u8; 32]> = (0..200).map(|_| random()).collect();
+        let mut roots = vec![];
+        for round in 0..4u8 {
+            for key in &keys[round as usize * 20..] {
+                trie.set(key, [round; 32]).unwrap();
+            }
+            roots.push(trie.commit().unwrap().canonicalize_root());
+        }
+        let db = trie.into_db().unwrap();
+        let before = db.tree.len();
+
+        // A root that is not stored stops the collection before anything is deleted
+        let unknown = random();
+        assert!(matches!(
+            db.collect_garbage(&[roots[3], unknown]),
+            Err(TrieError::MissingNode { hash, .. }) if hash == unknown
+        ));
+        assert_eq!(db.tree.len(), before);
+
+        let live = reachable(&db, &roots[2..]);
+        let stats = db.collect_garbage(&roots[2..]).unwrap();
+        assert_eq!(stats.live_entries, live.len());
+        assert_eq!(stats.reclaimed_entries, before - live.len());
+        assert!(stats.reclaimed_bytes > 32 * stats.reclaimed_entries as u64);
+        assert_eq!(db.tree.len(), live.len());
+
+        let again = db.collect_garbage(&roots[2..]).unwrap();
+        assert_eq!(again.reclaimed_entries, 0);
+
+        let trie = Trie::open(db, roots[2]);
+        assert_eq!(trie.iter().count(), 200);
+        assert_eq!(trie.get(keys[199]).unwrap(), Some(vec![2; 32]));
+        let trie = Trie::open(trie.into_db().unwrap(), roots[1]);
+        assert!(trie.get(keys[199]).is_err());
+    }
+}
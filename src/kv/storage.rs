@@ -1,15 +1,14 @@
 use std::fmt;
 
-use super::db::HashDB;
+use super::db::{HashDB, NodeBatch};
 use super::encoder::{RlpData, decode_rlp, encode_rlp};
-use crate::Key32;
-use crate::trie::{BranchNode, ExtensionNode, LeafNode, NibblePath, Node};
+use crate::trie::{BranchNode, ExtensionNode, LeafNode, NibblePath, Node, TrieError};
 use hex;
 use sha3::{Digest, Keccak256};
 
 #[derive(Debug)]
 pub enum CompactEncodeError {
-    InvalidNodeType { node: Node },
+    InvalidNodeType { node: Box<Node> },
 }
 
 #[derive(Debug)]
@@ -18,7 +17,7 @@ pub enum CompactDecodeError {
     EmptyPath,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum NodeRef {
     Hash([u8; 32]),
     Inline(Vec<u8>),
@@ -28,7 +27,7 @@ impl NodeRef {
     pub fn canonicalize_root(&self) -> [u8; 32] {
         match self {
             NodeRef::Hash(h) => *h,
-            NodeRef::Inline(bytes) => Keccak256::digest(&bytes).into(),
+            NodeRef::Inline(bytes) => Keccak256::digest(bytes).into(),
         }
     }
 }
@@ -65,72 +64,287 @@ impl fmt::Display for CompactEncodeError {
 
 impl std::error::Error for CompactEncodeError {}
 
-pub fn commit_node(db: &mut impl HashDB, node: &Node) -> NodeRef {
+impl std::error::Error for CompactDecodeError {}
+
+/// Keccak-256 of the RLP empty string (`0x80`), the root hash of a trie with no entries.
+pub const EMPTY_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+/// Encode `node` and write the hashed nodes beneath it (including itself) to `db` in one batch.
+/// Subtrees written by an earlier commit, or loaded from the db, are unchanged and skipped.
+pub fn commit_node(db: &mut impl HashDB, node: &Node) -> Result<NodeRef, TrieError> {
+    let (node_ref, batch) = stage_node(node);
+    db.write_batch(batch).map_err(TrieError::db)?;
+    mark_persisted(node);
+    Ok(node_ref)
+}
+
+/// Encode `node` and collect the hashed nodes that `commit_node` would write, without writing
+/// them or marking anything persisted. Call `mark_persisted` once the batch is stored.
+pub(crate) fn stage_node(node: &Node) -> (NodeRef, NodeBatch) {
+    #[cfg(feature = "parallel")]
+    hash_subtrees_in_parallel(node, available_threads());
+    let mut batch = vec![];
+    let node_ref = encode_node(
+        node,
+        &mut |hash, bytes| {
+            batch.push((hash, bytes));
+            Ok(())
+        },
+        true,
+    )
+    .unwrap_or_else(|_| unreachable!("staging writes nothing, so it cannot fail"));
+    (node_ref, batch)
+}
+
+/// Mark every node that `stage_node` collected as stored, so later commits skip it.
+pub(crate) fn mark_persisted(node: &Node) {
+    let Some(cache) = node.cache() else {
+        return;
+    };
+    if cache.is_persisted() {
+        return;
+    }
     match node {
-        Node::Leaf(leaf) => {
-            let encoded_path = compact_encode(node).unwrap();
+        Node::Extension(ext) => mark_persisted(&ext.child),
+        Node::Branch(branch) => branch
+            .children
+            .iter()
+            .flatten()
+            .for_each(|c| mark_persisted(c)),
+        Node::Leaf(_) | Node::Hash(_) => {}
+    }
+    cache.mark_persisted();
+}
 
-            println!("encoded_path: {:x?}", encoded_path);
-            println!("length of encoded_path: {}", encoded_path.len());
+/// Encode `node` exactly as `commit_node` would, without writing anything.
+pub fn hash_node(node: &Node) -> NodeRef {
+    #[cfg(feature = "parallel")]
+    hash_subtrees_in_parallel(node, available_threads());
+    hash_sequentially(node)
+}
 
-            let rlp = RlpData::List(vec![
-                RlpData::String(encoded_path),
-                RlpData::String(leaf.value.clone()),
-            ]);
+fn hash_sequentially(node: &Node) -> NodeRef {
+    encode_node(node, &mut |_, _| Ok(()), false)
+        .unwrap_or_else(|_| unreachable!("hashing writes nothing, so it cannot fail"))
+}
 
-            println!("rlp: {:x?}", rlp);
+/// Subtrees with fewer nodes to hash than this are left to the sequential pass, which hashes
+/// them faster than a thread could be started for them.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_NODES: usize = 1024;
 
-            inline_or_hash(db, rlp)
+#[cfg(feature = "parallel")]
+fn available_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+/// Hash the large children of the topmost branch under `node` on at most `threads` threads, so
+/// the sequential pass that follows finds them cached. Only that pass writes to the db, so the
+/// threads never share it.
+#[cfg(feature = "parallel")]
+fn hash_subtrees_in_parallel(node: &Node, threads: usize) {
+    let mut node = node;
+    while let Node::Extension(ext) = node {
+        node = &ext.child;
+    }
+    let Node::Branch(branch) = node else {
+        return;
+    };
+
+    let large: Vec<&Node> = branch
+        .children
+        .iter()
+        .flatten()
+        .map(|c| &**c)
+        .filter(|child| uncached_nodes(child, PARALLEL_MIN_NODES) >= PARALLEL_MIN_NODES)
+        .collect();
+    let threads = threads.min(large.len());
+    if threads < 2 {
+        return;
+    }
+
+    std::thread::scope(|scope| {
+        for share in large.chunks(large.len().div_ceil(threads)) {
+            scope.spawn(move || {
+                share
+                    .iter()
+                    .for_each(|child| drop(hash_sequentially(child)))
+            });
         }
-        Node::Extension(extension) => {
-            // commit the child first
-            let child_stored = commit_node(db, &extension.child);
-            let child_field = match child_stored {
-                NodeRef::Inline(bytes) => RlpData::String(bytes), // inline
-                NodeRef::Hash(h) => RlpData::String(h.to_vec()),  // 32-byte hash
-            };
-            let encoded_path = compact_encode(node).unwrap();
-            let rlp = RlpData::List(vec![RlpData::String(encoded_path), child_field]);
-            inline_or_hash(db, rlp)
+    });
+}
+
+/// How many nodes under `node` (itself included) have no cached encoding, counting no further
+/// than `limit`.
+#[cfg(feature = "parallel")]
+fn uncached_nodes(node: &Node, limit: usize) -> usize {
+    let mut count = 0;
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop()
+        && count < limit
+    {
+        // Hash references and cached subtrees have nothing left to hash
+        if node.cache().is_none_or(|cache| cache.get().is_some()) {
+            continue;
         }
-        Node::Branch(branch) => {
-            let mut items: Vec<RlpData> = Vec::with_capacity(17);
-            for i in 0..16 {
-                if let Some(child) = &branch.children[i] {
-                    let stored = commit_node(db, child);
-                    items.push(match stored {
-                        NodeRef::Inline(bytes) => RlpData::String(bytes),
-                        NodeRef::Hash(h) => RlpData::String(h.to_vec()),
-                    });
-                } else {
-                    items.push(RlpData::String(vec![])); // empty string for NULL
+        count += 1;
+        match node {
+            Node::Extension(ext) => stack.push(&ext.child),
+            Node::Branch(branch) => stack.extend(branch.children.iter().flatten().map(|c| &**c)),
+            Node::Leaf(_) | Node::Hash(_) => {}
+        }
+    }
+    count
+}
+
+/// Root hash of `node`: unlike children, the root is hashed even when its RLP is under 32 bytes.
+pub fn root_hash(node: Option<&Node>) -> [u8; 32] {
+    match node {
+        None => EMPTY_ROOT,
+        Some(n) => hash_node(n).canonicalize_root(),
+    }
+}
+
+/// Encode `node` bottom-up, handing each node that is referenced by hash to `sink`. Nodes with a
+/// cached encoding are not encoded again. When `commit` is set, every hashed node not yet
+/// persisted reaches `sink`; otherwise nothing below a cached node is visited.
+fn encode_node(
+    node: &Node,
+    sink: &mut impl FnMut([u8; 32], Vec<u8>) -> Result<(), TrieError>,
+    commit: bool,
+) -> Result<NodeRef, TrieError> {
+    // Already stored under its hash, nothing to write
+    if let Node::Hash(h) = node {
+        return Ok(NodeRef::Hash(*h));
+    }
+    let cache = node.cache().expect("resolved nodes carry a cache");
+
+    let node_ref = match cache.get() {
+        Some((_, node_ref)) if !commit || cache.is_persisted() => return Ok(node_ref.clone()),
+        Some((rlp, node_ref)) => {
+            // Hashed but never written: the encoding is known, the subtree is not yet stored
+            match node {
+                Node::Extension(ext) => {
+                    encode_node(&ext.child, sink, true)?;
                 }
+                Node::Branch(branch) => {
+                    for child in branch.children.iter().flatten() {
+                        encode_node(child, sink, true)?;
+                    }
+                }
+                Node::Leaf(_) | Node::Hash(_) => {}
             }
-            items.push(match &branch.value {
-                Some(v) => RlpData::String(v.clone()),
-                None => RlpData::String(vec![]),
-            });
+            if let NodeRef::Hash(h) = node_ref {
+                sink(*h, rlp.clone())?;
+            }
+            node_ref.clone()
+        }
+        None => {
+            let rlp = encode_rlp(&node_rlp(node, sink, commit)?);
+            let node_ref = inline_or_hash(sink, &rlp)?;
+            cache.set(rlp, node_ref.clone());
+            node_ref
+        }
+    };
+    Ok(node_ref)
+}
 
-            let rlp = RlpData::List(items);
-            inline_or_hash(db, rlp)
+/// The RLP encoding of a single node, with its children embedded or referenced by hash.
+/// Returns `None` for an unresolved hash reference, whose encoding lives in the db.
+pub fn encode_node_rlp(node: &Node) -> Option<Vec<u8>> {
+    let cache = node.cache()?;
+    hash_node(node);
+    cache.get().map(|(rlp, _)| rlp.clone())
+}
+
+fn node_rlp(
+    node: &Node,
+    sink: &mut impl FnMut([u8; 32], Vec<u8>) -> Result<(), TrieError>,
+    commit: bool,
+) -> Result<RlpData, TrieError> {
+    let rlp = match node {
+        Node::Leaf(leaf) => leaf_rlp(&leaf.path.nibbles, &leaf.value),
+        // commit the child first
+        Node::Extension(extension) => extension_rlp(
+            &extension.path.nibbles,
+            encode_node(&extension.child, sink, commit)?,
+        ),
+        Node::Branch(branch) => {
+            let mut children = Vec::with_capacity(16);
+            for child in &branch.children {
+                children.push(match child {
+                    Some(child) => Some(encode_node(child, sink, commit)?),
+                    None => None,
+                });
+            }
+            branch_rlp(children, branch.value.as_deref())
         }
+        Node::Hash(_) => unreachable!("hash references are encoded by their parent"),
+    };
+    Ok(rlp)
+}
+
+/// `[hp(path, leaf), value]`
+pub(crate) fn leaf_rlp(path: &[u8], value: &[u8]) -> RlpData {
+    RlpData::List(vec![
+        RlpData::String(hex_prefix(path, true)),
+        RlpData::String(value.to_vec()),
+    ])
+}
+
+/// `[hp(path, extension), child]`
+pub(crate) fn extension_rlp(path: &[u8], child: NodeRef) -> RlpData {
+    RlpData::List(vec![
+        RlpData::String(hex_prefix(path, false)),
+        child_field(child),
+    ])
+}
+
+/// `[child_0, ..., child_15, value]`, with the empty string for missing children and value.
+pub(crate) fn branch_rlp(
+    children: impl IntoIterator<Item = Option<NodeRef>>,
+    value: Option<&[u8]>,
+) -> RlpData {
+    let mut items: Vec<RlpData> = Vec::with_capacity(17);
+    for child in children {
+        match child {
+            Some(child) => items.push(child_field(child)),
+            None => items.push(RlpData::String(vec![])), // empty string for NULL
+        }
+    }
+    items.push(RlpData::String(value.unwrap_or_default().to_vec()));
+
+    RlpData::List(items)
+}
+
+/// How a child appears inside its parent: inline nodes are embedded as their RLP structure,
+/// hashed nodes as a 32-byte string.
+fn child_field(stored: NodeRef) -> RlpData {
+    match stored {
+        NodeRef::Inline(bytes) => decode_rlp(&bytes).expect("inline node is valid RLP"),
+        NodeRef::Hash(h) => RlpData::String(h.to_vec()),
     }
 }
 
-fn inline_or_hash<N: HashDB>(db: &mut N, rlp: RlpData) -> NodeRef {
-    let bytes = encode_rlp(&rlp);
-    println!("Raw RLP encoded data for node: {:x?}", bytes);
-    if bytes.len() < 32 {
-        NodeRef::Inline(bytes)
+/// Embed nodes under 32 bytes in their parent, hash (and hand to `sink`) anything larger.
+pub(crate) fn inline_or_hash(
+    sink: &mut impl FnMut([u8; 32], Vec<u8>) -> Result<(), TrieError>,
+    rlp: &[u8],
+) -> Result<NodeRef, TrieError> {
+    if rlp.len() < 32 {
+        Ok(NodeRef::Inline(rlp.to_vec()))
     } else {
-        let h: [u8; 32] = Keccak256::digest(&bytes).into();
-        let _ = db.put(h, bytes);
-        NodeRef::Hash(h)
+        let h: [u8; 32] = Keccak256::digest(rlp).into();
+        sink(h, rlp.to_vec())?;
+        Ok(NodeRef::Hash(h))
     }
 }
 
 fn compact_decode(encoded: &[u8]) -> Result<NibblePath, CompactDecodeError> {
-    let mut nibbles = NibblePath::from_bytes(encoded).nibbles;
+    let nibbles = NibblePath::from_bytes(encoded).nibbles;
 
     if nibbles.is_empty() {
         return Err(CompactDecodeError::EmptyPath);
@@ -139,89 +353,69 @@ fn compact_decode(encoded: &[u8]) -> Result<NibblePath, CompactDecodeError> {
     let flag = nibbles[0];
 
     if flag > 0x03 {
-        return Err(CompactDecodeError::InvalidFlag { flag: flag });
+        return Err(CompactDecodeError::InvalidFlag { flag });
     }
 
     Ok(NibblePath::new(
-        nibbles[(if flag % 2 == 0 { 2 } else { 1 })..].to_vec(),
+        nibbles[(if flag.is_multiple_of(2) { 2 } else { 1 })..].to_vec(),
     ))
 }
 
-fn compact_encode(node: &Node) -> Result<Vec<u8>, CompactEncodeError> {
-    let mut path_nibbles = Vec::new();
-    let mut encoded_path = Vec::new();
-
+/// Hex-prefix encode the path of a leaf or extension node.
+pub fn compact_encode(node: &Node) -> Result<Vec<u8>, CompactEncodeError> {
     match node {
-        Node::Leaf(leaf) => {
-            println!("Length of path: {}", leaf.path.nibbles.len());
-
-            let odd_len = (&leaf.path.nibbles.len() % 2) as u8;
-            path_nibbles.push(0x02 + odd_len);
-
-            if odd_len == 0 {
-                path_nibbles.push(0x00);
-            }
-
-            path_nibbles.extend_from_slice(&leaf.path.nibbles);
-        }
-        Node::Extension(extension) => {
-            let odd_len = (&extension.path.nibbles.len() % 2) as u8;
-            path_nibbles.push(0x00 + odd_len);
-
-            if odd_len == 0 {
-                path_nibbles.push(0x00);
-            }
-
-            path_nibbles.extend_from_slice(&extension.path.nibbles);
-        }
-        _ => {
-            return Err(CompactEncodeError::InvalidNodeType { node: node.clone() });
-        }
+        Node::Leaf(leaf) => Ok(hex_prefix(&leaf.path.nibbles, true)),
+        Node::Extension(extension) => Ok(hex_prefix(&extension.path.nibbles, false)),
+        _ => Err(CompactEncodeError::InvalidNodeType {
+            node: Box::new(node.clone()),
+        }),
     }
+}
 
-    for i in (0..path_nibbles.len()).step_by(2) {
-        encoded_path.push(path_nibbles[i] << 4 | path_nibbles[i + 1]);
+/// Hex-prefix encode `nibbles`: the flag nibble marks leaf vs extension and odd vs even length,
+/// padded with a zero nibble when the length is even.
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let odd_len = (nibbles.len() % 2) as u8;
+    let mut path_nibbles = Vec::with_capacity(nibbles.len() + 2);
+    path_nibbles.push(if leaf { 0x02 } else { 0x00 } + odd_len);
+
+    if odd_len == 0 {
+        path_nibbles.push(0x00);
     }
 
-    Ok(encoded_path)
-}
+    path_nibbles.extend_from_slice(nibbles);
 
-/// Read the hex-prefix (HP) flag nibble from the compact-encoded path bytes
-fn hp_flag(encoded_path: &[u8]) -> Option<u8> {
-    let nibbles = NibblePath::from_bytes(encoded_path).nibbles;
-    nibbles.first().copied()
+    path_nibbles
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect()
 }
 
-/// Distinguish inline bytes vs 32-byte hash, and load the child node accordingly.
-fn load_child(db: &impl HashDB, field: &RlpData) -> Option<Node> {
-    let bytes = match field {
-        RlpData::String(b) => b,
-        _ => return None,
-    };
-
-    if bytes.is_empty() {
-        // For children, empty means no child (this shouldn't occur for ext child).
-        return None;
-    }
-
-    if bytes.len() < 32 {
-        // Inline child: `bytes` are the child's **RLP**. Decode them and recurse.
-        let child_rlp = decode_rlp(bytes).ok()?;
-        parse_node(db, &child_rlp)
-    } else if bytes.len() == 32 {
-        // Hashed child: `bytes` are the 32-byte keccak of the child's RLP. Fetch from DB.
-        let h: [u8; 32] = bytes.as_slice().try_into().ok()?;
-        load_node(db, &h)
-    } else {
-        // Shouldn't happen in Ethereum MPT encoding
-        None
+/// Distinguish an inline child (embedded RLP list) from a 32-byte hash. Inline children are parsed
+/// straight away, hashed children are left as unresolved `Node::Hash` references.
+fn load_child(field: &RlpData) -> Result<Node, TrieError> {
+    match field {
+        // Inline child: the child's RLP structure is embedded directly in the parent.
+        RlpData::List(_) => parse_node(field),
+        // Hashed child: the 32-byte keccak of the child's RLP, loaded from the DB on demand.
+        RlpData::String(bytes) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(h) => Ok(Node::Hash(h)),
+            // Anything else shouldn't happen in Ethereum MPT encoding
+            Err(_) => Err(TrieError::MalformedNode {
+                reason: "child is neither an embedded node nor a 32-byte hash",
+            }),
+        },
     }
 }
 
-fn parse_node(db: &impl HashDB, rlp: &RlpData) -> Option<Node> {
+fn parse_node(rlp: &RlpData) -> Result<Node, TrieError> {
     let list = match rlp {
         RlpData::List(items) => items,
-        _ => return None, // top-level node must be a list
+        _ => {
+            return Err(TrieError::MalformedNode {
+                reason: "node is not a list",
+            });
+        }
     };
 
     match list.len() {
@@ -229,46 +423,43 @@ fn parse_node(db: &impl HashDB, rlp: &RlpData) -> Option<Node> {
             // Leaf or Extension
             let path_bytes = match &list[0] {
                 RlpData::String(b) => b.as_slice(),
-                _ => return None,
+                _ => {
+                    return Err(TrieError::MalformedNode {
+                        reason: "path is not a string",
+                    });
+                }
             };
-            println!("path_bytes: {:x?}", path_bytes);
-            let flag = hp_flag(path_bytes)?;
-            let path = compact_decode(path_bytes).ok()?; // your existing helper
+            let path = compact_decode(path_bytes)?;
 
-            println!("path: {:x?}", path);
-
-            if flag <= 0x01 {
+            // The hex-prefix flag is the high nibble of the first byte
+            if path_bytes[0] >> 4 <= 0x01 {
                 // Extension: [encoded_path, child_ref]
-                let child = load_child(db, &list[1])?;
-                Some(Node::Extension(ExtensionNode {
-                    path,
-                    child: Box::new(child),
-                }))
+                let child = load_child(&list[1])?;
+                Ok(Node::Extension(ExtensionNode::new(path, Box::new(child))))
             } else {
                 // Leaf: [encoded_path, value]
                 let value = match &list[1] {
                     RlpData::String(v) => v.clone(),
-                    _ => return None,
+                    _ => {
+                        return Err(TrieError::MalformedNode {
+                            reason: "leaf value is not a string",
+                        });
+                    }
                 };
-                println!("value: {:x?}", value);
-                Some(Node::Leaf(LeafNode { path, value }))
+                Ok(Node::Leaf(LeafNode::new(path, value)))
             }
         }
         17 => {
             // Branch: 16 children + value
             let mut branch = BranchNode::new();
 
-            for i in 0..16 {
-                match &list[i] {
+            for (i, item) in list[..16].iter().enumerate() {
+                match item {
                     RlpData::String(b) if b.is_empty() => { /* no child */ }
-                    RlpData::String(_) => {
-                        if let Some(child) = load_child(db, &list[i]) {
-                            branch.children[i] = Some(Box::new(child));
-                        } else {
-                            return None;
-                        }
+                    _ => {
+                        let child = load_child(item)?;
+                        branch.children[i] = Some(Box::new(child));
                     }
-                    _ => return None,
                 }
             }
 
@@ -276,34 +467,238 @@ fn parse_node(db: &impl HashDB, rlp: &RlpData) -> Option<Node> {
             branch.value = match &list[16] {
                 RlpData::String(v) if v.is_empty() => None,
                 RlpData::String(v) => Some(v.clone()),
-                _ => return None,
+                _ => {
+                    return Err(TrieError::MalformedNode {
+                        reason: "branch value is not a string",
+                    });
+                }
             };
 
-            Some(Node::Branch(branch))
+            Ok(Node::Branch(branch))
         }
-        _ => None,
+        _ => Err(TrieError::MalformedNode {
+            reason: "node list has neither 2 nor 17 items",
+        }),
+    }
+}
+
+/// Load a single node from the DB. Its hashed children stay unresolved.
+pub fn load_node(db: &impl HashDB, key: &[u8; 32]) -> Result<Node, TrieError> {
+    let encoded = db
+        .get(key)
+        .map_err(TrieError::db)?
+        .ok_or(TrieError::MissingNode {
+            hash: *key,
+            path: vec![],
+        })?;
+    let node = decode_node(&encoded)?;
+    // Already stored as is, so there is nothing to rehash or write back until it changes. A small
+    // root is stored under its hash too, but a parent would embed it, so leave that one alone.
+    if let Some(cache) = node.cache()
+        && encoded.len() >= 32
+    {
+        cache.set(encoded, NodeRef::Hash(*key));
+        cache.mark_persisted();
+    }
+    Ok(node)
+}
+
+/// Decode a single RLP-encoded node. Its hashed children stay unresolved.
+pub fn decode_node(encoded: &[u8]) -> Result<Node, TrieError> {
+    parse_node(&decode_rlp(encoded)?)
+}
+
+/// The hashes an encoded node refers its children by. Inline children are small enough that they
+/// never hold a hash themselves. Anything that does not decode as a node has none.
+#[cfg(feature = "sled")]
+pub(crate) fn hashed_children(encoded: &[u8]) -> Vec<[u8; 32]> {
+    match decode_node(encoded) {
+        Ok(node) => child_hashes(&node).into_iter().map(|(_, h)| h).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// The hashed children of a decoded node, each with the nibbles that lead to it from `node`.
+#[cfg(feature = "sled")]
+pub(crate) fn child_hashes(node: &Node) -> Vec<(Vec<u8>, [u8; 32])> {
+    match node {
+        Node::Branch(branch) => branch
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(nibble, child)| match child.as_deref() {
+                Some(Node::Hash(h)) => Some((vec![nibble as u8], *h)),
+                _ => None,
+            })
+            .collect(),
+        Node::Extension(ext) => match *ext.child {
+            Node::Hash(h) => vec![(ext.path.nibbles.clone(), h)],
+            _ => vec![],
+        },
+        Node::Leaf(_) | Node::Hash(_) => vec![],
+    }
+}
+
+/// Replace `node` with its stored contents if it is an unresolved hash reference.
+pub fn resolve(db: Option<&impl HashDB>, node: &mut Node) -> Result<(), TrieError> {
+    if let Node::Hash(h) = node {
+        *node = match db {
+            Some(db) => load_node(db, h)?,
+            None => {
+                return Err(TrieError::MissingNode {
+                    hash: *h,
+                    path: vec![],
+                });
+            }
+        };
     }
+    Ok(())
 }
 
-fn load_node(db: &impl HashDB, key: &[u8; 32]) -> Option<Node> {
-    let encoded = db.get(key).ok()??; // Result<Option<Vec<u8>>> → Option<Vec<u8>>
-    let rlp = decode_rlp(&encoded).ok()?; // RlpData
-    println!("rlp: {:x?}", rlp);
-    parse_node(db, &rlp) // Node
+/// Resolve every hash reference on `path` so the in-memory insert can walk it.
+pub fn resolve_path(
+    db: Option<&impl HashDB>,
+    node: &mut Node,
+    path: &[u8],
+) -> Result<(), TrieError> {
+    resolve_along(db, node, path, 0, false)
 }
 
-pub fn get_value(db: &impl HashDB, key: &[u8; 32], root_hash: &[u8; 32]) -> Option<Vec<u8>> {
-    let root = load_node(db, root_hash)?;
-    println!("root: {:x?}", root);
-    let path = NibblePath::from(Key32(*key));
-    println!("path to find: {:x?}", path);
-    root.get(path).cloned()
+/// Like `resolve_path`, but also resolves the siblings a delete may collapse into its parent:
+/// the children of any branch on the path with at most two entries.
+pub fn resolve_path_for_delete(
+    db: Option<&impl HashDB>,
+    node: &mut Node,
+    path: &[u8],
+) -> Result<(), TrieError> {
+    resolve_along(db, node, path, 0, true)
+}
+
+/// `depth` is how many nibbles of `path` lead to `node`.
+fn resolve_along(
+    db: Option<&impl HashDB>,
+    node: &mut Node,
+    path: &[u8],
+    depth: usize,
+    collapse: bool,
+) -> Result<(), TrieError> {
+    resolve(db, node).map_err(|e| e.at_path(&path[..depth]))?;
+
+    let rest = &path[depth..];
+    match node {
+        Node::Leaf(_) | Node::Hash(_) => Ok(()),
+        Node::Extension(ext) => match rest.strip_prefix(ext.path.nibbles.as_slice()) {
+            Some(_) => resolve_along(
+                db,
+                &mut ext.child,
+                path,
+                depth + ext.path.nibbles.len(),
+                collapse,
+            ),
+            None => Ok(()),
+        },
+        Node::Branch(branch) => {
+            if collapse {
+                let entries =
+                    branch.children.iter().flatten().count() + branch.value.is_some() as usize;
+                if entries <= 2 {
+                    for (nibble, child) in branch.children.iter_mut().enumerate() {
+                        if let Some(child) = child {
+                            resolve(db, child).map_err(|e| {
+                                e.at_path(&[&path[..depth], &[nibble as u8]].concat())
+                            })?;
+                        }
+                    }
+                }
+            }
+
+            match rest.first() {
+                Some(nibble) => match &mut branch.children[*nibble as usize] {
+                    Some(child) => resolve_along(db, child, path, depth + 1, collapse),
+                    None => Ok(()),
+                },
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+/// Look up `path` below `node`, loading unresolved nodes from `db` without keeping them.
+pub fn get_value(
+    db: Option<&impl HashDB>,
+    node: &Node,
+    path: &[u8],
+) -> Result<Option<Vec<u8>>, TrieError> {
+    get_value_at(db, node, path, 0)
+}
+
+/// `depth` is how many nibbles of `path` lead to `node`.
+fn get_value_at(
+    db: Option<&impl HashDB>,
+    node: &Node,
+    path: &[u8],
+    depth: usize,
+) -> Result<Option<Vec<u8>>, TrieError> {
+    let rest = &path[depth..];
+    match node {
+        Node::Hash(_) => {
+            let mut loaded = node.clone();
+            resolve(db, &mut loaded).map_err(|e| e.at_path(&path[..depth]))?;
+            get_value_at(db, &loaded, path, depth)
+        }
+        Node::Leaf(leaf) => Ok((leaf.path.nibbles == rest).then(|| leaf.value.clone())),
+        Node::Extension(ext) => match rest.strip_prefix(ext.path.nibbles.as_slice()) {
+            Some(_) => get_value_at(db, &ext.child, path, depth + ext.path.nibbles.len()),
+            None => Ok(None),
+        },
+        Node::Branch(branch) => match rest.first() {
+            None => Ok(branch.value.clone()),
+            Some(nibble) => match branch.children[*nibble as usize].as_deref() {
+                Some(child) => get_value_at(db, child, path, depth + 1),
+                None => Ok(None),
+            },
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::trie::{ExtensionNode, LeafNode, NibblePath, Node};
+    use crate::trie::{BranchNode, ExtensionNode, LeafNode, NibblePath, Node, Trie};
+
+    #[test]
+    fn staging_matches_sequential_encoding() {
+        // Large enough for the parallel build to hash several subtrees on their own threads
+        let keys: Vec<[u8; 32]> = (0..20_000).map(|_| rand::random()).collect();
+        let build = || {
+            let mut trie = Trie::new();
+            for key in &keys {
+                trie.set(key, &key[..8]).unwrap();
+            }
+            trie
+        };
+        let (staged, sequential) = (build(), build());
+
+        // Whatever this machine's core count, split the work up
+        #[cfg(feature = "parallel")]
+        hash_subtrees_in_parallel(staged.root().unwrap(), 4);
+        let (node_ref, mut batch) = stage_node(staged.root().unwrap());
+        let mut expected = vec![];
+        let expected_ref = encode_node(
+            sequential.root().unwrap(),
+            &mut |hash, bytes| {
+                expected.push((hash, bytes));
+                Ok(())
+            },
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(node_ref, expected_ref);
+        batch.sort();
+        expected.sort();
+        assert_eq!(batch, expected);
+    }
 
     #[test]
     fn compact_encode_leaf() {
@@ -348,4 +743,205 @@ mod tests {
             assert_eq!(decoded, *original_path);
         }
     }
+
+    #[test]
+    fn root_hash_matches_ethereum() {
+        // {"doe": "reindeer", "dog": "puppy", "dogglesworth": "cat"} from the Ethereum trie tests,
+        // which exercises an inline leaf and a branch value.
+        let dogglesworth = NibblePath::from_bytes(b"dogglesworth");
+        let mut dog = BranchNode::new();
+        dog.value = Some(b"puppy".to_vec());
+        dog.add_leaf(
+            6,
+            NibblePath::new(dogglesworth.nibbles[7..].to_vec()),
+            b"cat".to_vec(),
+        );
+
+        let mut branch = BranchNode::new();
+        branch.add_leaf(5, NibblePath::new(vec![]), b"reindeer".to_vec());
+        branch.add_child(7, Box::new(Node::Branch(dog)));
+
+        let root = Node::Extension(ExtensionNode::new(
+            NibblePath::new(vec![6, 4, 6, 0xf, 6]),
+            Box::new(Node::Branch(branch)),
+        ));
+
+        assert_eq!(
+            hex::encode(root_hash(Some(&root))),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+        assert_eq!(root_hash(None), EMPTY_ROOT);
+    }
+
+    /// Records writes so tests can see what a commit touches.
+    #[derive(Default)]
+    struct CountingDB {
+        nodes: std::sync::Mutex<std::collections::HashMap<[u8; 32], Vec<u8>>>,
+        puts: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingDB {
+        fn take_puts(&self) -> usize {
+            self.puts.swap(0, std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    impl HashDB for CountingDB {
+        type Error = std::convert::Infallible;
+
+        fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.nodes.lock().unwrap().get(key).cloned())
+        }
+
+        fn put(&self, key: [u8; 32], value: Vec<u8>) -> Result<(), Self::Error> {
+            self.puts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.nodes.lock().unwrap().insert(key, value);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn random_node(count: usize) -> (Node, Vec<[u8; 32]>) {
+        let keys: Vec<[u8; 32]> = (0..count).map(|_| rand::random()).collect();
+        let mut root = Node::new_leaf(NibblePath::from_bytes(&keys[0]), keys[0].to_vec());
+        for key in &keys[1..] {
+            root.insert(NibblePath::from_bytes(key), key.to_vec());
+        }
+        (root, keys)
+    }
+
+    #[test]
+    fn commit_writes_only_new_nodes() {
+        let mut db = CountingDB::default();
+        let (mut root, keys) = random_node(300);
+
+        commit_node(&mut db, &root).unwrap();
+        let first = db.take_puts();
+        assert_eq!(first, db.nodes.lock().unwrap().len());
+
+        commit_node(&mut db, &root).unwrap();
+        assert_eq!(db.take_puts(), 0);
+
+        // Only the nodes on the changed path are new
+        root.insert(NibblePath::from_bytes(&keys[7]), b"changed".to_vec());
+        commit_node(&mut db, &root).unwrap();
+        let written = db.take_puts();
+        assert!(written > 0 && written <= 6, "{written} writes");
+
+        // Hashing alone does not count as persisting
+        root.insert(NibblePath::from_bytes(&keys[8]), b"changed".to_vec());
+        hash_node(&root);
+        commit_node(&mut db, &root).unwrap();
+        assert!(db.take_puts() > 0);
+    }
+
+    #[test]
+    fn loaded_nodes_are_not_written_back() {
+        let mut db = CountingDB::default();
+        let (root, keys) = random_node(300);
+        let root_hash = commit_node(&mut db, &root).unwrap().canonicalize_root();
+        db.take_puts();
+
+        let mut root = load_node(&db, &root_hash).unwrap();
+        let path = NibblePath::from_bytes(&keys[3]);
+        resolve_path(Some(&db), &mut root, &path.nibbles).unwrap();
+        root.insert(path, b"changed".to_vec());
+
+        let new_root = commit_node(&mut db, &root).unwrap().canonicalize_root();
+        let written = db.take_puts();
+        assert!(written > 0 && written <= 6, "{written} writes");
+
+        let root = load_node(&db, &new_root).unwrap();
+        for key in &keys[..20] {
+            let path = NibblePath::from_bytes(key);
+            let expected = if key == &keys[3] {
+                b"changed".to_vec()
+            } else {
+                key.to_vec()
+            };
+            assert_eq!(
+                get_value(Some(&db), &root, &path.nibbles).unwrap(),
+                Some(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn commit_matches_hash_builder() {
+        let mut db = CountingDB::default();
+        let (root, mut keys) = random_node(2000);
+        keys.sort();
+        let mut builder = crate::trie::HashBuilder::new();
+        for key in &keys {
+            builder.add(key, key).unwrap();
+        }
+
+        let committed = commit_node(&mut db, &root).unwrap().canonicalize_root();
+        assert_eq!(committed, builder.root().unwrap());
+        assert_eq!(db.take_puts(), db.nodes.lock().unwrap().len());
+
+        // Every node on a path made it to the db
+        let loaded = load_node(&db, &committed).unwrap();
+        for key in &keys[..50] {
+            let path = NibblePath::from_bytes(key);
+            assert_eq!(
+                get_value(Some(&db), &loaded, &path.nibbles).unwrap(),
+                Some(key.to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn bad_nodes_are_typed_errors() {
+        assert!(matches!(decode_node(&[0xc1]), Err(TrieError::Rlp(_))));
+        // A three item list is neither a short node nor a branch
+        assert!(matches!(
+            decode_node(&encode_rlp(&RlpData::List(vec![
+                RlpData::String(vec![]);
+                3
+            ]))),
+            Err(TrieError::MalformedNode { .. })
+        ));
+        assert!(matches!(
+            decode_node(&encode_rlp(&RlpData::List(vec![
+                RlpData::String(vec![0x40]),
+                RlpData::String(vec![1]),
+            ]))),
+            Err(TrieError::CompactDecode(CompactDecodeError::InvalidFlag {
+                flag: 4
+            }))
+        ));
+
+        let mut db = CountingDB::default();
+        let (root, keys) = random_node(50);
+        let root_hash = commit_node(&mut db, &root).unwrap().canonicalize_root();
+        let mut root = load_node(&db, &root_hash).unwrap();
+
+        // Drop the child on the path of the first key, the lookup reports where it was needed
+        let path = NibblePath::from_bytes(&keys[0]).nibbles;
+        let Node::Branch(branch) = &root else {
+            panic!("50 random keys share no prefix")
+        };
+        let Some(&Node::Hash(child)) = branch.children[path[0] as usize].as_deref() else {
+            panic!("nodes holding 32-byte keys are hashed")
+        };
+        db.nodes.lock().unwrap().remove(&child);
+
+        let missing = get_value(Some(&db), &root, &path).unwrap_err();
+        assert!(matches!(
+            &missing,
+            TrieError::MissingNode { hash, path: at } if *hash == child && at == &path[..1]
+        ));
+        assert!(matches!(
+            resolve_path(Some(&db), &mut root, &path),
+            Err(TrieError::MissingNode { .. })
+        ));
+        assert!(matches!(
+            load_node(&db, &child),
+            Err(TrieError::MissingNode { .. })
+        ));
+    }
 }
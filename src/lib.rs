@@ -2,5 +2,11 @@ pub mod kv;
 pub mod trie;
 pub mod utils;
 
+pub use trie::BatchOp;
+pub use trie::HashBuilder;
+pub use trie::SecureTrie;
 pub use trie::Trie;
+pub use trie::TrieError;
+pub use trie::TrieView;
 pub use trie::path::Key32;
+pub use trie::{ProofError, RangeProof, verify_multiproof, verify_proof, verify_range_proof};
@@ -0,0 +1,378 @@
+use super::node::try_collapse_branch;
+use super::{BranchNode, DeleteResult, ExtensionNode, LeafNode, NibblePath, Node, TrieError};
+use crate::kv::db::HashDB;
+use crate::kv::storage::{resolve, resolve_path, resolve_path_for_delete};
+
+/// One write in a batch passed to `Trie::apply_batch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set a key to a value. An empty value deletes the key, as with `Trie::set`.
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set(key, _) | BatchOp::Delete(key) => key,
+        }
+    }
+}
+
+/// `Sync` with the `parallel` feature, where batch groups share the db across threads, and
+/// implemented by every type without it.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+
+/// `Sync` with the `parallel` feature, where batch groups share the db across threads, and
+/// implemented by every type without it.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// Groups with fewer ops than this are applied on the calling thread, which gets through them
+/// faster than a thread could be started for them.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_OPS: usize = 128;
+
+/// Apply `ops` to the trie at `root`, leaving it exactly as applying them one at a time would.
+///
+/// The root is opened up into a branch and the ops are grouped by the first nibble of their key,
+/// so each group only touches its own child of that branch. With the `parallel` feature each
+/// group of at least `PARALLEL_MIN_OPS` ops runs on its own thread. The root is collapsed back
+/// into canonical shape at the end.
+///
+/// If a node cannot be loaded, the group that needed it stops at that op and its error is
+/// returned once the other groups are done, so the trie keeps every op applied up to then.
+pub(crate) fn apply_batch<D: HashDB + MaybeSync>(
+    db: Option<&D>,
+    root: &mut Option<Node>,
+    ops: Vec<BatchOp>,
+) -> Result<(), TrieError> {
+    let mut ops: Vec<BatchOp> = ops
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set(key, value) if value.is_empty() => BatchOp::Delete(key),
+            op => op,
+        })
+        .collect();
+    // Later ops on a key replace earlier ones, so keep only the last op for each key
+    ops.reverse();
+    ops.sort_by(|a, b| a.key().cmp(b.key()));
+    ops.dedup_by(|later, first| later.key() == first.key());
+    if ops.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(root) = root {
+        resolve(db, root)?;
+    }
+    let mut branch = open_root(root.take());
+
+    // The empty key is the only one that ends at the root branch itself
+    let mut rest = ops.as_slice();
+    if let Some((op, tail)) = rest.split_first()
+        && op.key().is_empty()
+    {
+        branch.value = match op {
+            BatchOp::Set(_, value) => Some(value.clone()),
+            BatchOp::Delete(_) => None,
+        };
+        rest = tail;
+    }
+
+    let mut groups: Vec<(&mut Option<Box<Node>>, &[BatchOp])> = vec![];
+    let mut slots = branch.children.iter_mut().enumerate();
+    while let Some(op) = rest.first() {
+        let nibble = (op.key()[0] >> 4) as usize;
+        let len = rest.partition_point(|op| (op.key()[0] >> 4) as usize == nibble);
+        let (group, tail) = rest.split_at(len);
+        let slot = slots
+            .find(|(i, _)| *i == nibble)
+            .map(|(_, slot)| slot)
+            .expect("keys are sorted");
+        groups.push((slot, group));
+        rest = tail;
+    }
+
+    #[cfg(feature = "parallel")]
+    let applied = apply_in_parallel(db, groups);
+    #[cfg(not(feature = "parallel"))]
+    let applied = groups
+        .into_iter()
+        .map(|(slot, group)| apply_group(db, slot, group))
+        .fold(Ok(()), Result::and);
+
+    let loaded = resolve_lone_child(db, &mut branch);
+    *root = close_root(branch);
+    applied.and(loaded)
+}
+
+/// Apply the large groups on threads of their own and the small ones on the calling thread
+/// meanwhile. Errors are combined in nibble order, as the sequential path does.
+#[cfg(feature = "parallel")]
+fn apply_in_parallel(
+    db: Option<&(impl HashDB + Sync)>,
+    groups: Vec<(&mut Option<Box<Node>>, &[BatchOp])>,
+) -> Result<(), TrieError> {
+    let (large, small): (Vec<_>, Vec<_>) = groups
+        .into_iter()
+        .enumerate()
+        .partition(|(_, (_, group))| group.len() >= PARALLEL_MIN_OPS);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = large
+            .into_iter()
+            .map(|(i, (slot, group))| (i, scope.spawn(move || apply_group(db, slot, group))))
+            .collect();
+        let mut applied: Vec<_> = small
+            .into_iter()
+            .map(|(i, (slot, group))| (i, apply_group(db, slot, group)))
+            .collect();
+        applied.extend(
+            handles
+                .into_iter()
+                .map(|(i, handle)| (i, handle.join().expect("batch group panicked"))),
+        );
+        applied.sort_by_key(|(i, _)| *i);
+        applied
+            .into_iter()
+            .map(|(_, applied)| applied)
+            .fold(Ok(()), Result::and)
+    })
+}
+
+/// The root as a branch, possibly with a single child, so every key has a slot to go into.
+fn open_root(root: Option<Node>) -> BranchNode {
+    let Some(root) = root else {
+        return BranchNode::new();
+    };
+
+    let mut branch = BranchNode::new();
+    match root {
+        Node::Branch(mut root) => {
+            root.cache.clear();
+            return root;
+        }
+        Node::Leaf(leaf) => match leaf.path.nibbles.split_first() {
+            None => branch.value = Some(leaf.value),
+            Some((nibble, rest)) => {
+                branch.add_leaf(*nibble as usize, NibblePath::new(rest.to_vec()), leaf.value)
+            }
+        },
+        Node::Extension(ext) => match ext.path.nibbles.split_first() {
+            Some((nibble, [])) => branch.add_child(*nibble as usize, ext.child),
+            Some((nibble, rest)) => branch.add_child(
+                *nibble as usize,
+                Box::new(Node::Extension(ExtensionNode::new(
+                    NibblePath::new(rest.to_vec()),
+                    ext.child,
+                ))),
+            ),
+            None => unreachable!("extensions have a non-empty path"),
+        },
+        Node::Hash(_) => unreachable!("the root is resolved before it is opened"),
+    }
+    branch
+}
+
+/// A lone child may be pulled up into the root, so it has to be loaded.
+fn resolve_lone_child(db: Option<&impl HashDB>, branch: &mut BranchNode) -> Result<(), TrieError> {
+    let mut children = branch
+        .children
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(nibble, child)| child.as_mut().map(|child| (nibble, child)));
+    if let (Some((nibble, child)), None, None) = (children.next(), children.next(), &branch.value) {
+        resolve(db, child).map_err(|e| e.at_path(&[nibble as u8]))?;
+    }
+    Ok(())
+}
+
+/// Turn the root branch back into whatever a canonical trie has at its root.
+fn close_root(branch: BranchNode) -> Option<Node> {
+    let mut children = branch.children.iter().flatten();
+    match (children.next(), children.next(), &branch.value) {
+        (None, _, None) => return None,
+        // The lone child could not be loaded, keep the branch so nothing is lost
+        (Some(child), None, None) if matches!(**child, Node::Hash(_)) => {
+            return Some(Node::Branch(branch));
+        }
+        _ => {}
+    }
+
+    match try_collapse_branch(&branch) {
+        DeleteResult::DeletedAndReplace(node) => Some(node),
+        DeleteResult::DeletedAndRemove => None,
+        DeleteResult::Deleted | DeleteResult::NotFound => Some(Node::Branch(branch)),
+    }
+}
+
+/// Apply the ops of one group to the subtree in `slot`. Keys still include their first nibble.
+fn apply_group(
+    db: Option<&impl HashDB>,
+    slot: &mut Option<Box<Node>>,
+    ops: &[BatchOp],
+) -> Result<(), TrieError> {
+    for op in ops {
+        let nibbles = NibblePath::from_bytes(op.key()).nibbles;
+        let path = NibblePath::new(nibbles[1..].to_vec());
+        match (op, slot.as_deref_mut()) {
+            (BatchOp::Set(_, value), None) => {
+                *slot = Some(Box::new(Node::Leaf(LeafNode::new(path, value.clone()))));
+            }
+            (BatchOp::Set(_, value), Some(child)) => {
+                resolve_path(db, child, &path.nibbles).map_err(|e| prefixed(e, nibbles[0]))?;
+                child.insert(path, value.clone());
+            }
+            (BatchOp::Delete(_), None) => {}
+            (BatchOp::Delete(_), Some(child)) => {
+                resolve_path_for_delete(db, child, &path.nibbles)
+                    .map_err(|e| prefixed(e, nibbles[0]))?;
+                match child.delete(path) {
+                    DeleteResult::DeletedAndReplace(node) => *child = node,
+                    DeleteResult::DeletedAndRemove => *slot = None,
+                    DeleteResult::Deleted | DeleteResult::NotFound => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Paths inside a group start below the root branch, put the group's nibble back in front.
+fn prefixed(err: TrieError, nibble: u8) -> TrieError {
+    match err {
+        TrieError::MissingNode { hash, path } => TrieError::MissingNode {
+            hash,
+            path: [&[nibble], path.as_slice()].concat(),
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    use crate::trie::Trie;
+    use rand::random;
+
+    fn random_key() -> Vec<u8> {
+        // A small alphabet gives shared prefixes, prefix keys and the empty key
+        (0..random::<u8>() % 4)
+            .map(|_| [0x00, 0x01, 0x10, 0xff][random::<u8>() as usize % 4])
+            .collect()
+    }
+
+    fn random_ops(count: usize) -> Vec<BatchOp> {
+        (0..count)
+            .map(|_| {
+                if random::<u8>().is_multiple_of(3) {
+                    BatchOp::Delete(random_key())
+                } else {
+                    BatchOp::Set(
+                        random_key(),
+                        vec![random::<u8>(); 1 + random::<u8>() as usize % 40],
+                    )
+                }
+            })
+            .collect()
+    }
+
+    fn apply_one_by_one(trie: &mut Trie, ops: &[BatchOp]) {
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => trie.set(key, value).unwrap(),
+                BatchOp::Delete(key) => {
+                    trie.delete(key).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_one_by_one() {
+        for _ in 0..200 {
+            let initial = random_ops(random::<u8>() as usize % 20);
+            let ops = random_ops(random::<u8>() as usize % 60);
+
+            let mut batched = Trie::new();
+            let mut sequential = Trie::new();
+            apply_one_by_one(&mut batched, &initial);
+            apply_one_by_one(&mut sequential, &initial);
+
+            batched.apply_batch(ops.clone()).unwrap();
+            apply_one_by_one(&mut sequential, &ops);
+            assert_eq!(batched.root(), sequential.root());
+            assert_eq!(batched.root_hash(), sequential.root_hash());
+        }
+    }
+
+    #[test]
+    fn large_groups_match_one_by_one() {
+        // Enough keys that every group is large enough for a thread of its own
+        let ops: Vec<BatchOp> = (0..4000)
+            .map(|_| BatchOp::Set(random::<[u8; 8]>().to_vec(), vec![random()]))
+            .collect();
+
+        let mut batched = Trie::new();
+        let mut sequential = Trie::new();
+        batched.apply_batch(ops.clone()).unwrap();
+        apply_one_by_one(&mut sequential, &ops);
+        assert_eq!(batched.root_hash(), sequential.root_hash());
+    }
+
+    #[test]
+    fn deleting_everything_empties_the_trie() {
+        let mut trie = Trie::new();
+        trie.apply_batch([
+            BatchOp::Set(b"dog".to_vec(), b"puppy".to_vec()),
+            BatchOp::Set(b"doge".to_vec(), b"coin".to_vec()),
+            BatchOp::Set(b"horse".to_vec(), b"stallion".to_vec()),
+        ])
+        .unwrap();
+        trie.apply_batch([
+            BatchOp::Delete(b"dog".to_vec()),
+            BatchOp::Delete(b"doge".to_vec()),
+            BatchOp::Delete(b"horse".to_vec()),
+        ])
+        .unwrap();
+        assert_eq!(trie.root(), None);
+    }
+
+    #[test]
+    fn loads_subtrees_from_db() {
+        let keys: Vec<[u8; 32]> = (0..500).map(|_| random()).collect();
+
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let mut sequential = Trie::new();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+            sequential.set(key, key).unwrap();
+        }
+        let root = trie.commit().unwrap().canonicalize_root();
+        let db = trie.into_db().unwrap();
+
+        let ops: Vec<BatchOp> = keys[..200]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| match i % 2 {
+                0 => BatchOp::Delete(key.to_vec()),
+                _ => BatchOp::Set(key.to_vec(), b"changed".to_vec()),
+            })
+            .chain((0..200).map(|_| BatchOp::Set(random::<[u8; 32]>().to_vec(), vec![1])))
+            .collect();
+
+        let mut trie = Trie::open(db, root);
+        trie.apply_batch(ops.clone()).unwrap();
+        apply_one_by_one(&mut sequential, &ops);
+        assert_eq!(trie.root_hash(), sequential.root_hash());
+        assert_eq!(
+            trie.iter().map(Result::unwrap).collect::<Vec<_>>(),
+            sequential.iter().map(Result::unwrap).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use super::iter::SuspendedIter;
+use super::{Trie, TrieError, TrieIter};
+use crate::kv::db::{DefaultDB, HashDB};
+use std::ops::Bound;
+
+/// The key and value of the entry a cursor is on.
+type Entry<'e> = (&'e [u8], &'e [u8]);
+type OwnedEntry = (Vec<u8>, Vec<u8>);
+
+/// A position in a trie that can be moved with `seek`, `next` and `prev`, and whose entry can be
+/// updated or deleted in place. A step in the same direction as the last one carries on from the
+/// nodes that one had visited, so walking the whole trie loads every node once. `seek`, a change
+/// of direction or an edit start over with a pruned lookup from the root.
+///
+/// A cursor starts out unpositioned. From there `next` moves to the first entry and `prev` to the
+/// last one; moving past either end leaves the cursor unpositioned again.
+pub struct TrieCursor<'a, D: HashDB = DefaultDB> {
+    trie: &'a mut Trie<D>,
+    current: Option<OwnedEntry>,
+    walk: Option<SuspendedIter>, // the walk that reached `current`, until the trie changes
+}
+
+impl<'a, D: HashDB> TrieCursor<'a, D> {
+    pub fn new(trie: &'a mut Trie<D>) -> Self {
+        Self {
+            trie,
+            current: None,
+            walk: None,
+        }
+    }
+
+    /// Move to the first entry whose key is at or after `key`.
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Entry<'_>>, TrieError> {
+        self.walk = None;
+        let (current, walk) = step(self.trie.range(key.as_ref()..))?;
+        (self.current, self.walk) = (current, Some(walk));
+        Ok(self.entry())
+    }
+
+    // Not an `Iterator`: the entry borrows the cursor, which can still edit the trie
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Entry<'_>>, TrieError> {
+        let walk = match (self.walk.take(), &self.current) {
+            (Some(walk), Some(_)) if !walk.is_reverse() => self.resume(walk),
+            (_, Some((key, _))) => after(self.trie, key),
+            (_, None) => self.trie.iter(),
+        };
+        let (current, walk) = step(walk)?;
+        (self.current, self.walk) = (current, Some(walk));
+        Ok(self.entry())
+    }
+
+    pub fn prev(&mut self) -> Result<Option<Entry<'_>>, TrieError> {
+        let walk = match (self.walk.take(), &self.current) {
+            (Some(walk), Some(_)) if walk.is_reverse() => self.resume(walk),
+            (_, Some((key, _))) => self.trie.range_rev(..key.as_slice()),
+            (_, None) => self.trie.iter_rev(),
+        };
+        let (current, walk) = step(walk)?;
+        (self.current, self.walk) = (current, Some(walk));
+        Ok(self.entry())
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, value)| value.as_slice())
+    }
+
+    /// Replace the value of the current entry, or delete it like `delete` if `value` is empty.
+    /// Returns `false` if the cursor is unpositioned.
+    pub fn update(&mut self, value: impl AsRef<[u8]>) -> Result<bool, TrieError> {
+        if value.as_ref().is_empty() {
+            return self.delete();
+        }
+        match &mut self.current {
+            Some((key, current)) => {
+                self.walk = None;
+                self.trie.set(&key, &value)?;
+                *current = value.as_ref().to_vec();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Delete the current entry and move to the one after it.
+    /// Returns `false` if the cursor is unpositioned.
+    pub fn delete(&mut self) -> Result<bool, TrieError> {
+        match &self.current {
+            Some((key, _)) => {
+                let key = key.clone();
+                self.walk = None;
+                self.trie.delete(&key)?;
+                let (current, walk) = step(after(self.trie, &key))?;
+                (self.current, self.walk) = (current, Some(walk));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn entry(&self) -> Option<Entry<'_>> {
+        self.current
+            .as_ref()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    fn resume(&self, walk: SuspendedIter) -> TrieIter<'_, D> {
+        TrieIter::resume(self.trie.db(), self.trie.root(), walk)
+    }
+}
+
+fn after<'t, D: HashDB>(trie: &'t Trie<D>, key: &[u8]) -> TrieIter<'t, D> {
+    trie.range::<&[u8]>((Bound::Excluded(key), Bound::Unbounded))
+}
+
+/// The next entry of `walk`, and the walk let go of the trie to take the step after it.
+fn step<D: HashDB>(
+    mut walk: TrieIter<'_, D>,
+) -> Result<(Option<OwnedEntry>, SuspendedIter), TrieError> {
+    let entry = walk.next().transpose()?;
+    Ok((entry, walk.suspend()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    use rand::random;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A `MemoryDB` that counts the nodes read from it.
+    #[derive(Default)]
+    struct Counting(MemoryDB, AtomicUsize);
+
+    impl HashDB for Counting {
+        type Error = std::convert::Infallible;
+
+        fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Self::Error> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.get(key)
+        }
+
+        fn put(&self, key: [u8; 32], value: Vec<u8>) -> Result<(), Self::Error> {
+            self.0.put(key, value)
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn trie_with(keys: &[&[u8]]) -> Trie {
+        let mut trie = Trie::new();
+        for key in keys {
+            trie.set(key, key).unwrap();
+        }
+        trie
+    }
+
+    #[test]
+    fn seek_and_step() {
+        let mut trie = trie_with(&[b"do", b"dog", b"doge", b"horse"]);
+        let mut cursor = trie.cursor();
+
+        assert_eq!(cursor.key(), None);
+        assert_eq!(
+            cursor.seek(b"doe").unwrap().map(|(k, _)| k),
+            Some(&b"dog"[..])
+        );
+        assert_eq!(cursor.next().unwrap().map(|(k, _)| k), Some(&b"doge"[..]));
+        assert_eq!(cursor.next().unwrap().map(|(k, _)| k), Some(&b"horse"[..]));
+        assert_eq!(cursor.next().unwrap(), None);
+
+        // Off the end, prev starts again from the last entry
+        assert_eq!(cursor.prev().unwrap().map(|(k, _)| k), Some(&b"horse"[..]));
+        assert_eq!(
+            cursor.seek(b"dog").unwrap().map(|(k, _)| k),
+            Some(&b"dog"[..])
+        );
+        assert_eq!(cursor.prev().unwrap().map(|(k, _)| k), Some(&b"do"[..]));
+        assert_eq!(cursor.prev().unwrap(), None);
+        assert_eq!(cursor.seek(b"z").unwrap(), None);
+    }
+
+    #[test]
+    fn update_and_delete_in_place() {
+        let mut trie = trie_with(&[b"do", b"dog", b"doge", b"horse"]);
+        let mut cursor = trie.cursor();
+
+        cursor.seek(b"do").unwrap();
+        assert!(cursor.update(b"verb").unwrap());
+        assert_eq!(cursor.value(), Some(&b"verb"[..]));
+
+        // Deleting "dog" turns its branch back into a leaf for "doge"
+        cursor.next().unwrap();
+        assert!(cursor.delete().unwrap());
+        assert_eq!(cursor.key(), Some(&b"doge"[..]));
+        assert!(cursor.delete().unwrap());
+        assert_eq!(cursor.key(), Some(&b"horse"[..]));
+        cursor.next().unwrap();
+        assert!(!cursor.delete().unwrap());
+
+        let mut expected = Trie::new();
+        expected.set(b"do", b"verb").unwrap();
+        expected.set(b"horse", b"horse").unwrap();
+        assert_eq!(trie.root(), expected.root());
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn stepping_loads_each_node_once() {
+        let mut trie = Trie::from_db(Counting::default());
+        let mut keys: Vec<[u8; 8]> = (0..500).map(|_| random()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root = trie.commit().unwrap().canonicalize_root();
+        let mut db = trie.into_db().unwrap();
+        let stored = db.0.len();
+        keys.sort();
+
+        for reverse in [false, true] {
+            db.1.store(0, Ordering::Relaxed);
+            let mut trie = Trie::open(db, root);
+            let mut cursor = trie.cursor();
+            let mut seen = vec![];
+            loop {
+                let entry = if reverse {
+                    cursor.prev()
+                } else {
+                    cursor.next()
+                };
+                let Some((key, _)) = entry.unwrap() else {
+                    break;
+                };
+                seen.push(key.to_vec());
+            }
+            if reverse {
+                seen.reverse();
+            }
+            assert_eq!(seen, keys.iter().map(|k| k.to_vec()).collect::<Vec<_>>());
+            assert!(trie.db().unwrap().1.load(Ordering::Relaxed) <= stored);
+            db = trie.into_db().unwrap();
+        }
+    }
+
+    #[test]
+    fn merge_join_two_tries() {
+        let mut left = trie_with(&[b"a", b"b", b"d", b"f"]);
+        let mut right = trie_with(&[b"b", b"c", b"d", b"e"]);
+        let mut l = left.cursor();
+        let mut r = right.cursor();
+
+        let mut common = vec![];
+        let (mut lk, mut rk) = (
+            l.next().unwrap().map(|(k, _)| k.to_vec()),
+            r.next().unwrap().map(|(k, _)| k.to_vec()),
+        );
+        while let (Some(a), Some(b)) = (&lk, &rk) {
+            match a.cmp(b) {
+                std::cmp::Ordering::Less => lk = l.seek(b).unwrap().map(|(k, _)| k.to_vec()),
+                std::cmp::Ordering::Greater => rk = r.seek(a).unwrap().map(|(k, _)| k.to_vec()),
+                std::cmp::Ordering::Equal => {
+                    common.push(a.clone());
+                    lk = l.next().unwrap().map(|(k, _)| k.to_vec());
+                    rk = r.next().unwrap().map(|(k, _)| k.to_vec());
+                }
+            }
+        }
+
+        assert_eq!(common, vec![b"b".to_vec(), b"d".to_vec()]);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::kv::db::RootLabel;
+use crate::kv::encoder::RlpError;
+use crate::kv::storage::CompactDecodeError;
+use std::fmt;
+
+/// Everything that can go wrong reading, writing or walking a trie.
+#[derive(Debug)]
+pub enum TrieError {
+    /// The node store failed. Holds the store's own `HashDB::Error` as it was returned, which
+    /// its `PreimageDB` and `RootRegistry` share, so a caller gets it back with `downcast_ref`,
+    /// e.g. `err.downcast_ref::<sled::Error>()` with `SledDB`.
+    Db(Box<dyn std::error::Error + Send + Sync>),
+    /// The operation needs a node store and the trie has none
+    NoDb,
+    /// A stored node is not valid RLP
+    Rlp(RlpError),
+    /// A stored leaf or extension path is not valid hex-prefix encoding
+    CompactDecode(CompactDecodeError),
+    /// The node with this hash, found at this nibble path, is not in the store
+    MissingNode { hash: [u8; 32], path: Vec<u8> },
+    /// A node is valid RLP but not a leaf, extension or branch
+    MalformedNode { reason: &'static str },
+    /// A key given to `HashBuilder` does not sort after the one before it
+    UnsortedKey { key: Vec<u8> },
+    /// No root is registered under this label
+    UnknownRoot(RootLabel),
+}
+
+impl TrieError {
+    pub(crate) fn db(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        TrieError::Db(Box::new(err))
+    }
+
+    /// Fill in where a missing node was found, once the caller knows.
+    pub(crate) fn at_path(self, at: &[u8]) -> Self {
+        match self {
+            TrieError::MissingNode { hash, .. } => TrieError::MissingNode {
+                hash,
+                path: at.to_vec(),
+            },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for TrieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrieError::Db(err) => write!(f, "Database error: {}", err),
+            TrieError::NoDb => write!(f, "Trie has no database"),
+            TrieError::Rlp(err) => write!(f, "Invalid node RLP: {}", err),
+            TrieError::CompactDecode(err) => write!(f, "Invalid node path: {}", err),
+            TrieError::MissingNode { hash, path } => {
+                write!(f, "Missing node 0x{} at path {:?}", hex::encode(hash), path)
+            }
+            TrieError::MalformedNode { reason } => write!(f, "Malformed node: {}", reason),
+            TrieError::UnsortedKey { key } => {
+                write!(f, "Key 0x{} is out of order", hex::encode(key))
+            }
+            TrieError::UnknownRoot(label) => write!(f, "No root registered for {}", label),
+        }
+    }
+}
+
+impl std::error::Error for TrieError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrieError::Db(err) => Some(&**err),
+            TrieError::Rlp(err) => Some(err),
+            TrieError::CompactDecode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RlpError> for TrieError {
+    fn from(err: RlpError) -> Self {
+        TrieError::Rlp(err)
+    }
+}
+
+impl From<CompactDecodeError> for TrieError {
+    fn from(err: CompactDecodeError) -> Self {
+        TrieError::CompactDecode(err)
+    }
+}
@@ -0,0 +1,354 @@
+use super::{NibblePath, TrieError};
+use crate::kv::db::{DefaultDB, HashDB};
+use crate::kv::encoder::{RlpData, encode_rlp};
+use crate::kv::storage::{
+    EMPTY_ROOT, NodeRef, branch_rlp, extension_rlp, inline_or_hash, leaf_rlp,
+};
+use sha3::{Digest, Keccak256};
+
+/// Computes a root hash from `(key, value)` pairs added in strictly ascending key order, without
+/// building the trie in memory. Only the branches on the path of the last key are kept open, so
+/// memory is bounded by the depth of the trie. Produces the same nodes as `commit_node` would for
+/// a `Trie` holding the same entries, and can stream the hashed ones into a `HashDB`.
+pub struct HashBuilder<'a, D: HashDB = DefaultDB> {
+    db: Option<&'a D>,
+    /// Nibbles of the last key added. Its leaf is only placed once the next key shows how much
+    /// of the path the leaf owns.
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    /// Open branches on the path of `key`, by increasing depth
+    stack: Vec<OpenBranch>,
+}
+
+/// A branch whose children are not all known yet.
+struct OpenBranch {
+    depth: usize,
+    children: [Option<NodeRef>; 16],
+    value: Option<Vec<u8>>,
+}
+
+/// A finished subtree on the path of the last key, waiting to be placed in its parent.
+enum Pending {
+    Leaf(Vec<u8>),
+    Branch { depth: usize, node: NodeRef },
+}
+
+impl Default for HashBuilder<'_, DefaultDB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashBuilder<'_, DefaultDB> {
+    pub fn new() -> Self {
+        Self {
+            db: None,
+            key: vec![],
+            value: None,
+            stack: vec![],
+        }
+    }
+}
+
+impl<'a, D: HashDB> HashBuilder<'a, D> {
+    /// Write every hashed node to `db` as soon as it is finished. Finish with `commit` to keep
+    /// the trie: a store that prunes only ever reclaims the nodes of roots committed to it.
+    pub fn with_db(db: &'a D) -> Self {
+        Self {
+            db: Some(db),
+            key: vec![],
+            value: None,
+            stack: vec![],
+        }
+    }
+
+    /// Add the next entry. A `key` that does not sort after the previous one is rejected with
+    /// `TrieError::UnsortedKey` and leaves the builder as it was. An empty `value` adds nothing.
+    pub fn add(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), TrieError> {
+        let bytes = key.as_ref();
+        let key = NibblePath::from_bytes(bytes).nibbles;
+        if self.value.is_some() && key <= self.key {
+            return Err(TrieError::UnsortedKey {
+                key: bytes.to_vec(),
+            });
+        }
+        if value.as_ref().is_empty() {
+            return Ok(()); // No entry, as with `Trie::set`
+        }
+        let Some(prev_value) = self.value.take() else {
+            self.key = key;
+            self.value = Some(value.as_ref().to_vec());
+            return Ok(());
+        };
+
+        let prev_key = std::mem::replace(&mut self.key, key);
+        let common = prev_key
+            .iter()
+            .zip(&self.key)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if common == prev_key.len() {
+            // The previous key is a prefix of this one, so its value sits in a branch on this path
+            self.stack.push(OpenBranch::new(common, Some(prev_value)));
+        } else {
+            // Every branch below the divergence is complete; the divergence point needs a branch
+            let pending = self.close_from(&prev_key, common + 1, Pending::Leaf(prev_value))?;
+            if self.stack.last().is_none_or(|top| top.depth < common) {
+                self.stack.push(OpenBranch::new(common, None));
+            }
+            self.place(&prev_key, pending)?;
+        }
+        self.value = Some(value.as_ref().to_vec());
+        Ok(())
+    }
+
+    /// Finish the trie and return its root hash.
+    pub fn root(mut self) -> Result<[u8; 32], TrieError> {
+        let Some(value) = self.value.take() else {
+            return Ok(EMPTY_ROOT);
+        };
+        let key = std::mem::take(&mut self.key);
+        let pending = self.close_from(&key, 0, Pending::Leaf(value))?;
+        match self.finish(&key, 0, pending)? {
+            NodeRef::Hash(root) => Ok(root),
+            // The root is hashed even when it is small enough to be embedded
+            NodeRef::Inline(bytes) => {
+                let root = Keccak256::digest(&bytes).into();
+                if let Some(db) = self.db {
+                    db.put(root, bytes).map_err(TrieError::db)?;
+                }
+                Ok(root)
+            }
+        }
+    }
+
+    /// Finish the trie like `root`, then commit the root to the db and flush it, as
+    /// `Trie::commit` does. The root becomes the db's latest one and, with pruning, takes its
+    /// place among the retained roots.
+    pub fn commit(self) -> Result<[u8; 32], TrieError> {
+        let db = self.db.ok_or(TrieError::NoDb)?;
+        let root = self.root()?;
+        db.commit_root(root).map_err(TrieError::db)?;
+        db.flush().map_err(TrieError::db)?;
+        Ok(root)
+    }
+
+    /// Place `pending` into each open branch at `depth` or deeper, closing them from the bottom
+    /// up, and return the subtree that results.
+    fn close_from(
+        &mut self,
+        key: &[u8],
+        depth: usize,
+        mut pending: Pending,
+    ) -> Result<Pending, TrieError> {
+        while self.stack.last().is_some_and(|top| top.depth >= depth) {
+            self.place(key, pending)?;
+            let branch = self.stack.pop().expect("checked above");
+            let rlp = branch_rlp(branch.children, branch.value.as_deref());
+            pending = Pending::Branch {
+                depth: branch.depth,
+                node: self.store(rlp)?,
+            };
+        }
+        Ok(pending)
+    }
+
+    /// Put `pending` into the open branch on top of the stack, in the slot `key` leads to.
+    fn place(&mut self, key: &[u8], pending: Pending) -> Result<(), TrieError> {
+        let depth = self
+            .stack
+            .last()
+            .expect("an open branch to place into")
+            .depth;
+        let node = self.finish(key, depth + 1, pending)?;
+        self.stack.last_mut().expect("checked above").children[key[depth] as usize] = Some(node);
+        Ok(())
+    }
+
+    /// Encode `pending` as the node found `depth` nibbles into `key`: a leaf owns the rest of the
+    /// path, a branch deeper down is reached through an extension.
+    fn finish(&mut self, key: &[u8], depth: usize, pending: Pending) -> Result<NodeRef, TrieError> {
+        match pending {
+            Pending::Leaf(value) => self.store(leaf_rlp(&key[depth..], &value)),
+            Pending::Branch { depth: at, node } if at == depth => Ok(node),
+            Pending::Branch { depth: at, node } => self.store(extension_rlp(&key[depth..at], node)),
+        }
+    }
+
+    fn store(&mut self, rlp: RlpData) -> Result<NodeRef, TrieError> {
+        let db = self.db;
+        inline_or_hash(
+            &mut |hash, bytes| match db {
+                Some(db) => db.put(hash, bytes).map_err(TrieError::db),
+                None => Ok(()),
+            },
+            &encode_rlp(&rlp),
+        )
+    }
+}
+
+impl OpenBranch {
+    fn new(depth: usize, value: Option<Vec<u8>>) -> Self {
+        Self {
+            depth,
+            children: Default::default(),
+            value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    #[cfg(feature = "sled")]
+    use crate::kv::db::SledDB;
+    use crate::kv::db::{MemoryDB, RootRegistry};
+    #[cfg(feature = "sled")]
+    use crate::kv::sled_db::tests::TempStore;
+    use crate::trie::Trie;
+    use rand::random;
+    use std::collections::BTreeMap;
+
+    fn build(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> [u8; 32] {
+        let mut builder = HashBuilder::new();
+        for (key, value) in entries {
+            builder.add(key, value).unwrap();
+        }
+        builder.root().unwrap()
+    }
+
+    #[test]
+    fn matches_ethereum_roots() {
+        let dogs: BTreeMap<Vec<u8>, Vec<u8>> = [
+            ("doe", "reindeer"),
+            ("dog", "puppy"),
+            ("dogglesworth", "cat"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+        assert_eq!(
+            hex::encode(build(&dogs)),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+
+        // "do" is a prefix of "dog" and "doge", so its value lives in a branch
+        let puppy: BTreeMap<Vec<u8>, Vec<u8>> = [
+            ("do", "verb"),
+            ("horse", "stallion"),
+            ("doge", "coin"),
+            ("dog", "puppy"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+        assert_eq!(
+            hex::encode(build(&puppy)),
+            "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        );
+
+        assert_eq!(build(&BTreeMap::new()), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn matches_trie_on_random_keys() {
+        for _ in 0..20 {
+            // Short keys over a small alphabet give lots of shared prefixes and prefix keys
+            let entries: BTreeMap<Vec<u8>, Vec<u8>> = (0..random::<u8>())
+                .map(|_| {
+                    let key: Vec<u8> = (0..random::<u8>() % 4)
+                        .map(|_| random::<u8>() % 3)
+                        .collect();
+                    let value = vec![random::<u8>() | 1; 1 + random::<u8>() as usize % 40];
+                    (key, value)
+                })
+                .collect();
+
+            let mut trie = Trie::new();
+            for (key, value) in &entries {
+                trie.set(key, value).unwrap();
+            }
+            assert_eq!(build(&entries), trie.root_hash());
+        }
+    }
+
+    #[test]
+    fn streams_nodes_into_db() {
+        let db = MemoryDB::new();
+        let entries: BTreeMap<[u8; 32], [u8; 32]> =
+            (0..300).map(|_| (random(), random())).collect();
+
+        let mut builder = HashBuilder::with_db(&db);
+        for (key, value) in &entries {
+            builder.add(key, value).unwrap();
+        }
+        let root = builder.root().unwrap();
+
+        let trie = Trie::open(db, root);
+        let stored: Vec<_> = trie.iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<_> = entries
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn commit_makes_the_root_the_latest() {
+        let db = MemoryDB::new();
+        let mut builder = HashBuilder::with_db(&db);
+        builder.add("dog", "puppy").unwrap();
+        let root = builder.commit().unwrap();
+        assert_eq!(db.latest_root().unwrap(), Some(root));
+
+        assert!(matches!(HashBuilder::new().commit(), Err(TrieError::NoDb)));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn commit_lets_a_pruned_store_reclaim_old_tries() {
+        let path = TempStore::new("mpt-hash-builder-pruned");
+        let db = SledDB::open_pruned(&path, "mpt", 1).unwrap();
+        let mut roots = vec![];
+        for _ in 0..3 {
+            let entries: BTreeMap<[u8; 32], [u8; 32]> =
+                (0..100).map(|_| (random(), random())).collect();
+            let mut builder = HashBuilder::with_db(&db);
+            for (key, value) in &entries {
+                builder.add(key, value).unwrap();
+            }
+            roots.push(builder.commit().unwrap());
+        }
+        assert_eq!(db.latest_root().unwrap(), Some(roots[2]));
+
+        // Pruning already removed the older tries, so only the last one is live
+        assert_eq!(db.collect_garbage(&[]).unwrap().reclaimed_entries, 0);
+        let trie = Trie::open(db, roots[2]);
+        assert_eq!(trie.iter().count(), 100);
+        let trie = Trie::open(trie.into_db().unwrap(), roots[0]);
+        assert!(matches!(
+            trie.iter().next(),
+            Some(Err(TrieError::MissingNode { .. }))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsorted_keys() {
+        let mut builder = HashBuilder::new();
+        builder.add("dog", "puppy").unwrap();
+        assert!(matches!(
+            builder.add("doe", "reindeer"),
+            Err(TrieError::UnsortedKey { key }) if key == b"doe"
+        ));
+        assert!(matches!(
+            builder.add("dog", "again"),
+            Err(TrieError::UnsortedKey { .. })
+        ));
+
+        // The rejected entries left nothing behind
+        let mut expected = Trie::new();
+        expected.set("dog", "puppy").unwrap();
+        assert_eq!(builder.root().unwrap(), expected.root_hash());
+    }
+}
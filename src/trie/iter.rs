@@ -0,0 +1,326 @@
+use super::{NibblePath, Node, TrieError};
+use crate::kv::db::{DefaultDB, HashDB};
+use crate::kv::storage::load_node;
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// A node still to be visited, or an entry found while visiting one.
+enum Pending<'a> {
+    Node(Cow<'a, Node>, Vec<u8>), // node and the nibbles leading to it
+    Resident(Vec<u8>),            // in-memory node of a suspended walk, found again by its nibbles
+    Entry(Vec<u8>, Vec<u8>),
+}
+
+/// In-order walk over a trie's `(key, value)` pairs, in lexicographic nibble order (or the
+/// reverse). In-memory nodes are borrowed, unresolved ones are loaded from the db and dropped once
+/// visited. Subtrees that lie entirely outside the bounds or the prefix are skipped without being
+/// loaded. A node that cannot be loaded is yielded as an error, which ends the walk.
+pub struct TrieIter<'a, D: HashDB = DefaultDB> {
+    db: Option<&'a D>,
+    root: Option<&'a Node>,
+    stack: Vec<Pending<'a>>,
+    lower: Bound<Vec<u8>>, // nibbles
+    upper: Bound<Vec<u8>>, // nibbles
+    prefix: Vec<u8>,       // nibbles
+    reverse: bool,
+}
+
+impl<'a, D: HashDB> TrieIter<'a, D> {
+    pub(crate) fn new(db: Option<&'a D>, root: Option<&'a Node>) -> Self {
+        let stack = root
+            .map(|root| Pending::Node(Cow::Borrowed(root), vec![]))
+            .into_iter()
+            .collect();
+        Self {
+            db,
+            root,
+            stack,
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+            prefix: vec![],
+            reverse: false,
+        }
+    }
+
+    /// Only yield keys within the given byte bounds.
+    pub(crate) fn with_range(mut self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Self {
+        let to_nibbles = |key: &[u8]| NibblePath::from_bytes(key).nibbles;
+        self.lower = lower.map(to_nibbles);
+        self.upper = upper.map(to_nibbles);
+        self
+    }
+
+    /// Only yield keys whose nibble path starts with `prefix`.
+    pub(crate) fn with_prefix(mut self, prefix: &NibblePath) -> Self {
+        self.prefix = prefix.nibbles.clone();
+        self
+    }
+
+    /// Yield keys from the largest to the smallest.
+    pub(crate) fn reversed(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// Let go of the trie, keeping what is left to visit. Nodes loaded from the db are kept as
+    /// they are, in-memory ones by their path.
+    pub(crate) fn suspend(self) -> SuspendedIter {
+        let stack = self.stack.into_iter().map(|pending| match pending {
+            Pending::Node(Cow::Owned(node), path) => Pending::Node(Cow::Owned(node), path),
+            Pending::Node(Cow::Borrowed(_), path) | Pending::Resident(path) => {
+                Pending::Resident(path)
+            }
+            Pending::Entry(key, value) => Pending::Entry(key, value),
+        });
+        SuspendedIter {
+            stack: stack.collect(),
+            lower: self.lower,
+            upper: self.upper,
+            prefix: self.prefix,
+            reverse: self.reverse,
+        }
+    }
+
+    /// Carry on with a suspended walk over the trie at `root`, which must not have changed since.
+    pub(crate) fn resume(db: Option<&'a D>, root: Option<&'a Node>, walk: SuspendedIter) -> Self {
+        Self {
+            db,
+            root,
+            stack: walk.stack,
+            lower: walk.lower,
+            upper: walk.upper,
+            prefix: walk.prefix,
+            reverse: walk.reverse,
+        }
+    }
+
+    /// Whether any key starting with `path` can fall within the bounds and the prefix.
+    fn may_contain(&self, path: &[u8]) -> bool {
+        let shares_prefix = path.starts_with(&self.prefix) || self.prefix.starts_with(path);
+        // Every key below `path` is greater than or equal to `path`
+        let below_lower = match &self.lower {
+            Bound::Included(l) | Bound::Excluded(l) => path < l.as_slice() && !l.starts_with(path),
+            Bound::Unbounded => false,
+        };
+        let above_upper = match &self.upper {
+            Bound::Included(u) => path > u.as_slice(),
+            Bound::Excluded(u) => path >= u.as_slice(),
+            Bound::Unbounded => false,
+        };
+        shares_prefix && !below_lower && !above_upper
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let above_lower = match &self.lower {
+            Bound::Included(l) => key >= l.as_slice(),
+            Bound::Excluded(l) => key > l.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let below_upper = match &self.upper {
+            Bound::Included(u) => key <= u.as_slice(),
+            Bound::Excluded(u) => key < u.as_slice(),
+            Bound::Unbounded => true,
+        };
+        key.starts_with(&self.prefix) && above_lower && below_upper
+    }
+
+    fn push_node(&mut self, node: Cow<'a, Node>, path: Vec<u8>) {
+        if self.may_contain(&path) {
+            self.stack.push(Pending::Node(node, path));
+        }
+    }
+
+    fn push_entry(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if self.contains(&key) {
+            self.stack.push(Pending::Entry(key, value));
+        }
+    }
+
+    /// Push what `node` contains so that its entries pop off the stack in key order.
+    fn expand(&mut self, node: Cow<'a, Node>, mut prefix: Vec<u8>) -> Result<(), TrieError> {
+        match node {
+            Cow::Borrowed(&Node::Hash(h)) | Cow::Owned(Node::Hash(h)) => {
+                let loaded = match self.db {
+                    Some(db) => load_node(db, &h).map_err(|e| e.at_path(&prefix))?,
+                    None => {
+                        return Err(TrieError::MissingNode {
+                            hash: h,
+                            path: prefix,
+                        });
+                    }
+                };
+                self.push_node(Cow::Owned(loaded), prefix);
+            }
+            Cow::Borrowed(Node::Leaf(leaf)) => {
+                prefix.extend_from_slice(&leaf.path.nibbles);
+                self.push_entry(prefix, leaf.value.clone());
+            }
+            Cow::Owned(Node::Leaf(leaf)) => {
+                prefix.extend_from_slice(&leaf.path.nibbles);
+                self.push_entry(prefix, leaf.value);
+            }
+            Cow::Borrowed(Node::Extension(ext)) => {
+                prefix.extend_from_slice(&ext.path.nibbles);
+                self.push_node(Cow::Borrowed(&ext.child), prefix);
+            }
+            Cow::Owned(Node::Extension(ext)) => {
+                prefix.extend_from_slice(&ext.path.nibbles);
+                self.push_node(Cow::Owned(*ext.child), prefix);
+            }
+            Cow::Borrowed(Node::Branch(branch)) => {
+                let children = branch
+                    .children
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, child)| Some((i, Cow::Borrowed(child.as_deref()?))));
+                self.push_branch(prefix, children, branch.value.clone());
+            }
+            Cow::Owned(Node::Branch(branch)) => {
+                let children = branch
+                    .children
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, child)| Some((i, Cow::Owned(*child?))));
+                self.push_branch(prefix, children, branch.value);
+            }
+        }
+        Ok(())
+    }
+
+    /// The branch value's key is a prefix of all its children's keys, so it comes first going
+    /// forwards and last going backwards. Whatever comes first is pushed last.
+    fn push_branch(
+        &mut self,
+        prefix: Vec<u8>,
+        children: impl DoubleEndedIterator<Item = (usize, Cow<'a, Node>)>,
+        value: Option<Vec<u8>>,
+    ) {
+        if self.reverse {
+            if let Some(value) = value {
+                self.push_entry(prefix.clone(), value);
+            }
+            for (i, child) in children {
+                self.push_node(child, child_prefix(&prefix, i));
+            }
+        } else {
+            for (i, child) in children.rev() {
+                self.push_node(child, child_prefix(&prefix, i));
+            }
+            if let Some(value) = value {
+                self.push_entry(prefix, value);
+            }
+        }
+    }
+}
+
+/// A `TrieIter` that does not borrow the trie, see `TrieIter::suspend`.
+pub(crate) struct SuspendedIter {
+    stack: Vec<Pending<'static>>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    prefix: Vec<u8>,
+    reverse: bool,
+}
+
+impl SuspendedIter {
+    pub(crate) fn is_reverse(&self) -> bool {
+        self.reverse
+    }
+}
+
+/// The in-memory node whose keys start with `path`.
+fn resident<'a>(root: Option<&'a Node>, path: &[u8]) -> Option<&'a Node> {
+    let (mut node, mut depth) = (root?, 0);
+    while depth < path.len() {
+        match node {
+            Node::Branch(branch) => node = branch.children[path[depth] as usize].as_deref()?,
+            Node::Extension(ext) => {
+                depth += ext.path.nibbles.len() - 1;
+                node = &ext.child;
+            }
+            _ => return None,
+        }
+        depth += 1;
+    }
+    (depth == path.len()).then_some(node)
+}
+
+fn child_prefix(prefix: &[u8], nibble: usize) -> Vec<u8> {
+    let mut path = prefix.to_vec();
+    path.push(nibble as u8);
+    path
+}
+
+impl<D: HashDB> Iterator for TrieIter<'_, D> {
+    type Item = Result<(Vec<u8>, Vec<u8>), TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(pending) = self.stack.pop() {
+            match pending {
+                Pending::Entry(path, value) => {
+                    return Some(Ok((NibblePath::new(path).to_bytes(), value)));
+                }
+                Pending::Node(node, prefix) => {
+                    if let Err(err) = self.expand(node, prefix) {
+                        self.stack.clear();
+                        return Some(Err(err));
+                    }
+                }
+                Pending::Resident(prefix) => {
+                    let Some(node) = resident(self.root, &prefix) else {
+                        self.stack.clear();
+                        let reason = "suspended walk resumed on a changed trie";
+                        return Some(Err(TrieError::MalformedNode { reason }));
+                    };
+                    if let Err(err) = self.expand(Cow::Borrowed(node), prefix) {
+                        self.stack.clear();
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    use crate::trie::{BranchNode, LeafNode};
+
+    #[test]
+    fn out_of_range_subtrees_are_not_loaded() {
+        // Without a db, visiting any of the unresolved children would fail
+        let mut branch = BranchNode::new();
+        for i in 0..16 {
+            branch.add_child(i, Box::new(Node::Hash([i as u8; 32])));
+        }
+        branch.children[4] = Some(Box::new(Node::Leaf(LeafNode::new(
+            NibblePath::new(vec![2]),
+            b"value".to_vec(),
+        ))));
+        let root = Node::Branch(branch);
+
+        let forward: Vec<_> = TrieIter::<MemoryDB>::new(None, Some(&root))
+            .with_range(Bound::Included(&[0x40]), Bound::Excluded(&[0x43]))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(forward, vec![(vec![0x42], b"value".to_vec())]);
+
+        let prefix = NibblePath::new(vec![4]);
+        let reverse: Vec<_> = TrieIter::<MemoryDB>::new(None, Some(&root))
+            .with_prefix(&prefix)
+            .reversed()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reverse, forward);
+
+        let mut all = TrieIter::<MemoryDB>::new(None, Some(&root));
+        assert!(matches!(
+            all.next(),
+            Some(Err(TrieError::MissingNode { hash, path })) if hash == [0; 32] && path == [0]
+        ));
+        assert!(all.next().is_none());
+    }
+}
@@ -1,7 +1,24 @@
+pub mod batch;
+pub mod cursor;
+pub mod error;
+pub mod hash_builder;
+pub mod iter;
 pub mod node;
 pub mod path;
+pub mod proof;
+pub mod secure;
+#[allow(clippy::module_inception)]
 pub mod trie;
+pub mod view;
 
+pub use batch::BatchOp;
+pub use cursor::TrieCursor;
+pub use error::TrieError;
+pub use hash_builder::HashBuilder;
+pub use iter::TrieIter;
 pub use node::{BranchNode, DeleteResult, ExtensionNode, LeafNode, Node};
 pub use path::{Key32, NibblePath};
+pub use proof::{ProofError, RangeProof, verify_multiproof, verify_proof, verify_range_proof};
+pub use secure::{SecureEntry, SecureTrie, hash_key};
 pub use trie::Trie;
+pub use view::TrieView;
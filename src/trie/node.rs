@@ -1,23 +1,30 @@
-use super::NibblePath;
+use super::{NibblePath, TrieError};
+use crate::kv::storage::NodeRef;
 use std::array;
+use std::fmt;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 //--- Node Kinds ---
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BranchNode {
     pub children: [Option<Box<Node>>; 16], // 0-15 nibbles
     pub value: Option<Vec<u8>>,            // vt
+    pub(crate) cache: NodeCache,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ExtensionNode {
     pub path: NibblePath,
     pub child: Box<Node>,
+    pub(crate) cache: NodeCache,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LeafNode {
     pub path: NibblePath,
     pub value: Vec<u8>,
+    pub(crate) cache: NodeCache,
 }
 
 //--- Merkle Patricia Node ---
@@ -26,6 +33,69 @@ pub enum Node {
     Branch(BranchNode),
     Extension(ExtensionNode),
     Leaf(LeafNode),
+    Hash([u8; 32]), // Unresolved reference to a node stored in a HashDB
+}
+
+/// A node's RLP and the reference its parent holds to it, filled in the first time the node is
+/// encoded and cleared whenever the node or anything beneath it changes. Also remembers whether
+/// the node is already in the db, so commits can skip it. Always compares equal, so nodes compare
+/// by their contents alone.
+#[derive(Default)]
+pub struct NodeCache {
+    encoded: OnceLock<Box<(Vec<u8>, NodeRef)>>,
+    persisted: AtomicBool,
+}
+
+impl NodeCache {
+    pub(crate) fn get(&self) -> Option<&(Vec<u8>, NodeRef)> {
+        self.encoded.get().map(|cached| &**cached)
+    }
+
+    pub(crate) fn set(&self, rlp: Vec<u8>, node_ref: NodeRef) {
+        let _ = self.encoded.set(Box::new((rlp, node_ref)));
+    }
+
+    /// True once this node, and with it everything beneath it, has been written to the db.
+    pub(crate) fn is_persisted(&self) -> bool {
+        self.persisted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_persisted(&self) {
+        self.persisted.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Clone for NodeCache {
+    fn clone(&self) -> Self {
+        Self {
+            encoded: self.encoded.clone(),
+            persisted: AtomicBool::new(self.is_persisted()),
+        }
+    }
+}
+
+impl PartialEq for NodeCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for NodeCache {}
+
+impl fmt::Debug for NodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some((_, node_ref)) if self.is_persisted() => {
+                write!(f, "NodeCache({}, persisted)", node_ref)
+            }
+            Some((_, node_ref)) => write!(f, "NodeCache({})", node_ref),
+            None => write!(f, "NodeCache(dirty)"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -33,6 +103,13 @@ pub enum DeleteResult {
     NotFound,                // Key wasn't found
     Deleted,                 // Key was deleted
     DeletedAndReplace(Node), // Key was deleted, replace this node with the returned node
+    DeletedAndRemove,        // Key was deleted and nothing is left, remove this node
+}
+
+impl Default for BranchNode {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BranchNode {
@@ -40,6 +117,7 @@ impl BranchNode {
         Self {
             children: array::from_fn(|_| None),
             value: None,
+            cache: NodeCache::default(),
         }
     }
 
@@ -55,17 +133,21 @@ impl BranchNode {
 
 impl ExtensionNode {
     pub fn new(path: NibblePath, child: Box<Node>) -> Self {
-        Self { path, child }
+        Self {
+            path,
+            child,
+            cache: NodeCache::default(),
+        }
     }
-    pub fn merge_with(mut self, path: NibblePath, value: Vec<u8>) -> Node {
+    pub(crate) fn merge_with(mut self, path: NibblePath, value: Vec<u8>) -> Node {
         let a = &self.path.nibbles;
-        let b = &path.nibbles[..a.len()];
-        let k = self.path.lcp_len(b);
+        let k = self.path.lcp_len(&path.nibbles);
 
         if k == a.len() {
             //with an identical extension we will need to insert the rest of the path to the extensions child
             let rem_path = &path.nibbles[k..];
 
+            self.cache.clear();
             self.child.insert(
                 NibblePath {
                     nibbles: rem_path.to_vec(),
@@ -73,15 +155,16 @@ impl ExtensionNode {
                 value.clone(),
             );
 
-            return Node::Extension(self);
+            Node::Extension(self)
         } else {
             let mut branch = BranchNode::new();
 
             // Here we need to create a new extension with the remaining path if the path is not empty.
+            // k < a.len() here, so the extension keeps at least the nibble that picks the branch slot.
 
             let rem_ext_path = self.path.nibbles[k..].to_vec();
 
-            if !rem_ext_path.is_empty() {
+            if rem_ext_path.len() > 1 {
                 branch.add_child(
                     rem_ext_path[0] as usize,
                     Box::new(Node::Extension(ExtensionNode::new(
@@ -93,7 +176,7 @@ impl ExtensionNode {
                 );
             } else {
                 branch.add_child(
-                    a[k] as usize,
+                    rem_ext_path[0] as usize,
                     std::mem::replace(&mut self.child, Box::new(Node::dummy())),
                 );
             }
@@ -130,7 +213,11 @@ impl ExtensionNode {
 
 impl LeafNode {
     pub fn new(path: NibblePath, value: Vec<u8>) -> Self {
-        Self { path, value }
+        Self {
+            path,
+            value,
+            cache: NodeCache::default(),
+        }
     }
 
     pub fn diverge_with(&self, path: NibblePath, value: Vec<u8>) -> Node {
@@ -185,7 +272,7 @@ impl LeafNode {
             return Node::Extension(ext);
         }
 
-        return Node::Branch(branch);
+        Node::Branch(branch)
     }
 }
 
@@ -202,19 +289,57 @@ impl Node {
         match self {
             Node::Leaf(leaf) => Some(&leaf.path),
             Node::Extension(ext) => Some(&ext.path),
-            Node::Branch(_) => None,
+            Node::Branch(_) | Node::Hash(_) => None,
+        }
+    }
+
+    /// The cached encoding of this node, or `None` for an unresolved hash reference.
+    pub(crate) fn cache(&self) -> Option<&NodeCache> {
+        match self {
+            Node::Branch(branch) => Some(&branch.cache),
+            Node::Extension(ext) => Some(&ext.cache),
+            Node::Leaf(leaf) => Some(&leaf.cache),
+            Node::Hash(_) => None,
+        }
+    }
+
+    fn clear_cache(&mut self) {
+        match self {
+            Node::Branch(branch) => branch.cache.clear(),
+            Node::Extension(ext) => ext.cache.clear(),
+            Node::Leaf(leaf) => leaf.cache.clear(),
+            Node::Hash(_) => {}
+        }
+    }
+
+    pub(crate) fn delete(&mut self, path: NibblePath) -> DeleteResult {
+        // Every node on the path of a successful delete changes beneath it
+        let result = self.delete_from(path);
+        if !matches!(result, DeleteResult::NotFound) {
+            self.clear_cache();
         }
+        result
     }
 
-    pub fn delete(&mut self, path: NibblePath) -> DeleteResult {
+    fn delete_from(&mut self, path: NibblePath) -> DeleteResult {
         match self {
-            Node::Leaf(_) => {
-                panic!("Delete should not be called directly on a leaf node");
+            Node::Leaf(leaf) => {
+                if leaf.path == path {
+                    DeleteResult::DeletedAndRemove
+                } else {
+                    DeleteResult::NotFound
+                }
+            }
+            Node::Hash(_) => {
+                unreachable!("hash references are resolved before delete");
             }
 
             Node::Branch(branch) => {
                 if path.nibbles.is_empty() {
-                    branch.value = None;
+                    // The key ends at this branch, its value lives in the branch itself
+                    if branch.value.take().is_none() {
+                        return DeleteResult::NotFound;
+                    }
                     return try_collapse_branch(branch);
                 }
 
@@ -225,16 +350,6 @@ impl Node {
                         nibbles: path.nibbles[1..].to_vec(),
                     };
 
-                    if let Node::Leaf(leaf) = &**child {
-                        if leaf.path == rem_path {
-                            *child_opt = None;
-                            // Check if branch needs collapsing
-                            return try_collapse_branch(branch);
-                        } else {
-                            return DeleteResult::NotFound;
-                        }
-                    }
-
                     let delete_result = child.delete(rem_path);
 
                     match delete_result {
@@ -249,6 +364,11 @@ impl Node {
                             // Check if branch still needs collapsing
                             try_collapse_branch(branch)
                         }
+                        DeleteResult::DeletedAndRemove => {
+                            *child_opt = None;
+                            // Check if branch needs collapsing
+                            try_collapse_branch(branch)
+                        }
                     }
                 } else {
                     DeleteResult::NotFound
@@ -266,6 +386,8 @@ impl Node {
                     match ext.child.delete(rem_path) {
                         DeleteResult::NotFound => DeleteResult::NotFound,
                         DeleteResult::Deleted => DeleteResult::Deleted,
+                        // Nothing left below the extension, so the extension goes too
+                        DeleteResult::DeletedAndRemove => DeleteResult::DeletedAndRemove,
                         DeleteResult::DeletedAndReplace(new_child) => {
                             // Check if we can merge with the new child
                             match new_child {
@@ -284,9 +406,9 @@ impl Node {
                                         ExtensionNode::new(merged_path, child_ext.child),
                                     ))
                                 }
-                                Node::Branch(_) => {
+                                Node::Branch(_) | Node::Hash(_) => {
                                     // Extension → Branch: keep as is
-                                    ext.child = Box::new(new_child);
+                                    *ext.child = new_child;
                                     DeleteResult::Deleted
                                 }
                             }
@@ -299,21 +421,29 @@ impl Node {
         }
     }
 
-    pub fn get(&self, path: NibblePath) -> Option<&Vec<u8>> {
+    /// The value stored at `path` below this node. Hash references are not loaded from any db,
+    /// so reaching one on the path gives `TrieError::MissingNode`.
+    pub fn get(&self, path: NibblePath) -> Result<Option<&Vec<u8>>, TrieError> {
+        let nibbles = path.nibbles.clone();
+        self.get_from(path)
+            .map_err(|(hash, rest)| TrieError::MissingNode {
+                hash,
+                path: nibbles[..nibbles.len() - rest].to_vec(),
+            })
+    }
+
+    /// `get`, failing with the hash reference it reached and how many nibbles were left.
+    fn get_from(&self, path: NibblePath) -> Result<Option<&Vec<u8>>, ([u8; 32], usize)> {
         //If self is a leaf node we just need to get the value if the key matches
         //If self is an extension we need to pattern match the path to the extension then follow it to the next node
         //If self is a branch we need to get the child at the first nibble of the path and then recursively call get on that child
 
         match self {
             Node::Leaf(leaf) => {
-                println!("We matched with a leaf so just get the value if the key is the same");
-                println!("leaf.path: {:x?}", leaf.path);
-                println!("path: {:x?}", path);
                 if leaf.path == path {
-                    print!("WE matched");
-                    Some(&leaf.value)
+                    Ok(Some(&leaf.value))
                 } else {
-                    None
+                    Ok(None)
                 }
             }
             Node::Extension(ext) => {
@@ -327,14 +457,14 @@ impl Node {
                         nibbles: path.nibbles[ext.path.nibbles.len()..].to_vec(),
                     };
 
-                    ext.child.get(rem_path)
+                    ext.child.get_from(rem_path)
                 } else {
-                    None
+                    Ok(None)
                 }
             }
             Node::Branch(branch) => {
                 if path.nibbles.is_empty() {
-                    return branch.value.as_ref(); // for get()
+                    return Ok(branch.value.as_ref()); // for get()
                 }
 
                 let child_opt = &branch.children[path.nibbles[0] as usize];
@@ -344,15 +474,19 @@ impl Node {
                         nibbles: path.nibbles[1..].to_vec(),
                     };
 
-                    child.get(rem_path)
+                    child.get_from(rem_path)
                 } else {
-                    None
+                    Ok(None)
                 }
             }
+            // Unresolved nodes have to be loaded from the db, see `storage::get_value`
+            Node::Hash(hash) => Err((*hash, path.nibbles.len())),
         }
     }
 
-    pub fn insert(&mut self, path: NibblePath, value: Vec<u8>) {
+    pub(crate) fn insert(&mut self, path: NibblePath, value: Vec<u8>) {
+        // Clear before the node is taken apart below, so a node reused as is does not keep it
+        self.clear_cache();
         match self {
             Node::Branch(branch) => {
                 // If we are inserting into a branch node we are going to do one of the following:
@@ -363,6 +497,12 @@ impl Node {
                 //       ii) branch -> extension -> branch -> leaves >> branch -> extension -> branch -> extension -> branch -> leaves
                 //    c) if it is a leaf node then we check to see whether we need to create a branch or an extension from that by comparing the leaf node path to our new path
                 // 2. If we dont have a child for the first nibble of the path we create a new leaf node and add it to the branch node
+                // 3. If the path ends here the key is a prefix of other keys, so the value is stored in the branch itself
+
+                if path.nibbles.is_empty() {
+                    branch.value = Some(value);
+                    return;
+                }
 
                 let child_opt = &mut branch.children[path.nibbles[0] as usize];
 
@@ -394,7 +534,7 @@ impl Node {
                                 );
                             }
                         }
-                        Node::Branch(_) => {
+                        Node::Branch(_) | Node::Hash(_) => {
                             let rem_path = NibblePath {
                                 nibbles: path.nibbles[1..].to_vec(),
                             };
@@ -411,7 +551,6 @@ impl Node {
                         },
                         value,
                     );
-                    return;
                 }
             }
             Node::Extension(_) => {
@@ -426,11 +565,14 @@ impl Node {
                 // So here we need to create a new branch node that has two child leaf nodes
                 *self = leaf.diverge_with(path, value);
             }
+            Node::Hash(_) => {
+                unreachable!("hash references are resolved before insert");
+            }
         }
     }
 }
 
-fn try_collapse_branch(branch: &BranchNode) -> DeleteResult {
+pub(crate) fn try_collapse_branch(branch: &BranchNode) -> DeleteResult {
     let active_children: Vec<_> = branch
         .children
         .iter()
@@ -438,16 +580,26 @@ fn try_collapse_branch(branch: &BranchNode) -> DeleteResult {
         .filter_map(|(i, child)| child.as_ref().map(|c| (i, c)))
         .collect();
 
+    if active_children.is_empty() {
+        // Only the branch value is left: Branch → Leaf with an empty path
+        return match &branch.value {
+            Some(value) => DeleteResult::DeletedAndReplace(Node::Leaf(LeafNode::new(
+                NibblePath { nibbles: vec![] },
+                value.clone(),
+            ))),
+            None => DeleteResult::DeletedAndRemove,
+        };
+    }
+
     if active_children.len() == 1 && branch.value.is_none() {
         // Branch with single child and no value should collapse
         let (nibble, child) = active_children[0];
 
         // Create appropriate collapsed node based on child type
-        return match &**child {
+        match &**child {
             Node::Leaf(leaf) => {
                 // Branch → Leaf: create leaf with extended path
                 let mut new_path = vec![nibble as u8];
-                println!("new path: {:?}", new_path);
                 new_path.extend_from_slice(&leaf.path.nibbles);
                 DeleteResult::DeletedAndReplace(Node::Leaf(LeafNode::new(
                     NibblePath { nibbles: new_path },
@@ -472,7 +624,10 @@ fn try_collapse_branch(branch: &BranchNode) -> DeleteResult {
                     child.clone(),
                 )))
             }
-        };
+            Node::Hash(_) => {
+                unreachable!("hash references are resolved before delete");
+            }
+        }
     } else {
         // Branch has multiple children or has a value with children
         // No collapse needed
@@ -516,4 +671,23 @@ mod unit_tests {
         // Should create an extension with branch
         assert!(matches!(result, Node::Extension(_)));
     }
+
+    #[test]
+    fn get_reports_unresolved_hash_references() {
+        let mut branch = BranchNode::new();
+        branch.add_leaf(1, NibblePath { nibbles: vec![2] }, b"loaded".to_vec());
+        branch.add_child(3, Box::new(Node::Hash([7; 32])));
+        let node = Node::Extension(ExtensionNode::new(
+            NibblePath { nibbles: vec![0] },
+            Box::new(Node::Branch(branch)),
+        ));
+
+        let get = |nibbles: Vec<u8>| node.get(NibblePath { nibbles });
+        assert_eq!(get(vec![0, 1, 2]).unwrap(), Some(&b"loaded".to_vec()));
+        assert_eq!(get(vec![0, 4]).unwrap(), None);
+        assert!(matches!(
+            get(vec![0, 3, 5, 6]),
+            Err(TrieError::MissingNode { hash, path }) if hash == [7; 32] && path == [0, 3]
+        ));
+    }
 }
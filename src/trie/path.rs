@@ -4,6 +4,12 @@ use sha3::{Digest, Keccak256};
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Key32(pub [u8; 32]);
 
+impl AsRef<[u8]> for Key32 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl From<String> for Key32 {
     fn from(s: String) -> Self {
         Key32(Keccak256::digest(s.as_bytes()).into())
@@ -41,13 +47,11 @@ impl NibblePath {
     }
 
     pub fn lcp_len(&self, other: &[u8]) -> usize {
-        let n = self.nibbles.len().min(other.len());
-        for i in 0..n {
-            if self.nibbles[i] != other[i] {
-                return i;
-            }
-        }
-        n
+        self.nibbles
+            .iter()
+            .zip(other)
+            .take_while(|(a, b)| a == b)
+            .count()
     }
 
     pub fn from_bytes(bytes: &[u8]) -> NibblePath {
@@ -59,10 +63,17 @@ impl NibblePath {
 
         NibblePath { nibbles }
     }
+
+    /// Pack the nibbles back into bytes. Full key paths always have an even number of nibbles.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+            .collect()
+    }
 }
 
 #[cfg(test)]
-
 mod unit_tests {
     use super::*;
     use rand::random;
@@ -109,6 +120,13 @@ mod unit_tests {
         assert_eq!(a.lcp_len(&b.nibbles), 3);
     }
 
+    #[test]
+    fn nibbles_to_bytes_round_trip() {
+        let bytes = random::<[u8; 32]>();
+        assert_eq!(NibblePath::from_bytes(&bytes).to_bytes(), bytes.to_vec());
+        assert_eq!(NibblePath::new(vec![]).to_bytes(), Vec::<u8>::new());
+    }
+
     #[test]
     fn bytes_to_nibbles_conversion() {
         let bytes = b"test";
@@ -0,0 +1,674 @@
+use super::{NibblePath, Node, TrieError};
+use crate::kv::db::HashDB;
+use crate::kv::storage::{EMPTY_ROOT, decode_node, encode_node_rlp, root_hash};
+use sha3::{Digest, Keccak256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The entries of a trie between two boundary keys, with the proof nodes for both boundaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeProof {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// Why a proof failed to verify.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProofError {
+    /// The proof node at `index` does not hash to the reference held by its parent (or the root)
+    HashMismatch {
+        index: usize,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The proof node at `index` is not a valid RLP-encoded trie node
+    MalformedNode { index: usize },
+    /// The path needs the node with this hash, but the proof has run out
+    MissingNode { hash: [u8; 32] },
+    /// Range entry `index` lies outside the boundary keys
+    EntryOutOfRange { index: usize },
+    /// Range entry `index` is not strictly greater than the one before it
+    UnorderedEntries { index: usize },
+    /// The trie rebuilt from a range proof hashes to `actual` instead of the root
+    RootMismatch { actual: [u8; 32] },
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::HashMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Proof node {} hashes to 0x{}, expected 0x{}",
+                index,
+                hex::encode(actual),
+                hex::encode(expected)
+            ),
+            ProofError::MalformedNode { index } => write!(f, "Malformed proof node {}", index),
+            ProofError::MissingNode { hash } => {
+                write!(f, "Proof is missing node 0x{}", hex::encode(hash))
+            }
+            ProofError::EntryOutOfRange { index } => {
+                write!(f, "Range entry {} lies outside the proven range", index)
+            }
+            ProofError::UnorderedEntries { index } => {
+                write!(f, "Range entry {} is not in ascending key order", index)
+            }
+            ProofError::RootMismatch { actual } => {
+                write!(f, "Range rebuilds to root 0x{}", hex::encode(actual))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+/// Collect the RLP-encoded nodes on the path from `root` towards `key`, in the shape of
+/// EIP-1186 (`eth_getProof`): the root is always included, other nodes only when their parent
+/// references them by hash. Nodes under 32 bytes are embedded in their parent and so are already
+/// covered by it.
+pub(crate) fn get_proof(
+    db: Option<&impl HashDB>,
+    root: Option<&Node>,
+    key: &[u8],
+) -> Result<Vec<Vec<u8>>, TrieError> {
+    let mut proof = vec![];
+    if let Some(root) = root {
+        let path = NibblePath::from_bytes(key);
+        prove_node(db, root, &path.nibbles, 0, &mut proof)?;
+    }
+    Ok(proof)
+}
+
+/// The proofs for all of `keys` merged into one list, each node appearing once.
+pub(crate) fn get_multiproof<K: AsRef<[u8]>>(
+    db: Option<&impl HashDB>,
+    root: Option<&Node>,
+    keys: &[K],
+) -> Result<Vec<Vec<u8>>, TrieError> {
+    let mut seen = HashSet::new();
+    let mut proof = vec![];
+    for key in keys {
+        for encoded in get_proof(db, root, key.as_ref())? {
+            if seen.insert(<[u8; 32]>::from(Keccak256::digest(&encoded))) {
+                proof.push(encoded);
+            }
+        }
+    }
+    Ok(proof)
+}
+
+/// Prove `node`, which sits `depth` nibbles along `path`.
+fn prove_node(
+    db: Option<&impl HashDB>,
+    node: &Node,
+    path: &[u8],
+    depth: usize,
+    proof: &mut Vec<Vec<u8>>,
+) -> Result<(), TrieError> {
+    if let Node::Hash(h) = node {
+        let missing = || TrieError::MissingNode {
+            hash: *h,
+            path: path[..depth].to_vec(),
+        };
+        // Hashed nodes are stored exactly as they appear in a proof
+        let encoded = db
+            .ok_or_else(missing)?
+            .get(h)
+            .map_err(TrieError::db)?
+            .ok_or_else(missing)?;
+        let loaded = decode_node(&encoded)?;
+        proof.push(encoded);
+        return prove_child(db, &loaded, path, depth, proof);
+    }
+
+    let encoded = encode_node_rlp(node).expect("resolved nodes always have an encoding");
+    if depth == 0 || encoded.len() >= 32 {
+        proof.push(encoded);
+    }
+    prove_child(db, node, path, depth, proof)
+}
+
+/// Continue the proof into the child of `node` that `path` leads to, if there is one.
+fn prove_child(
+    db: Option<&impl HashDB>,
+    node: &Node,
+    path: &[u8],
+    depth: usize,
+    proof: &mut Vec<Vec<u8>>,
+) -> Result<(), TrieError> {
+    let rest = &path[depth..];
+    match node {
+        Node::Leaf(_) | Node::Hash(_) => Ok(()),
+        Node::Extension(ext) => match rest.strip_prefix(ext.path.nibbles.as_slice()) {
+            Some(_) => prove_node(db, &ext.child, path, depth + ext.path.nibbles.len(), proof),
+            None => Ok(()),
+        },
+        Node::Branch(branch) => match rest.first() {
+            Some(nibble) => match &branch.children[*nibble as usize] {
+                Some(child) => prove_node(db, child, path, depth + 1, proof),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        },
+    }
+}
+
+/// Verify a proof produced by `Trie::get_proof` against `root` and return the value it proves for
+/// `key`, or `None` if it proves the key is absent. Needs neither a `Trie` nor a `HashDB`: every
+/// node is checked against the keccak held by its parent (the first against `root`) and decoded
+/// on its own.
+pub fn verify_proof(
+    root: [u8; 32],
+    key: impl AsRef<[u8]>,
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    if root == EMPTY_ROOT && proof.is_empty() {
+        return Ok(None);
+    }
+
+    let path = NibblePath::from_bytes(key.as_ref());
+    let mut proof_nodes = proof.iter().enumerate();
+    walk_path(root, &path.nibbles, |expected| {
+        // Hashed nodes on the path come from the proof in order
+        let (index, encoded) = proof_nodes
+            .next()
+            .ok_or(ProofError::MissingNode { hash: expected })?;
+        let actual: [u8; 32] = Keccak256::digest(encoded).into();
+        if actual != expected {
+            return Err(ProofError::HashMismatch {
+                index,
+                expected,
+                actual,
+            });
+        }
+        decode_node(encoded).map_err(|_| ProofError::MalformedNode { index })
+    })
+}
+
+/// Verify a proof produced by `Trie::get_multiproof` for all of `keys` at once, returning the
+/// value (or `None` for an absent key) for each key in order. Every proof node is hashed and
+/// decoded once, however many of the keys share it.
+pub fn verify_multiproof<K: AsRef<[u8]>>(
+    root: [u8; 32],
+    keys: &[K],
+    proof: &[Vec<u8>],
+) -> Result<Vec<Option<Vec<u8>>>, ProofError> {
+    if root == EMPTY_ROOT && proof.is_empty() {
+        return Ok(vec![None; keys.len()]);
+    }
+
+    let mut nodes = HashMap::new();
+    for (index, encoded) in proof.iter().enumerate() {
+        let node = decode_node(encoded).map_err(|_| ProofError::MalformedNode { index })?;
+        nodes.insert(<[u8; 32]>::from(Keccak256::digest(encoded)), node);
+    }
+
+    keys.iter()
+        .map(|key| {
+            let path = NibblePath::from_bytes(key.as_ref());
+            walk_path(root, &path.nibbles, |hash| {
+                nodes
+                    .get(&hash)
+                    .cloned()
+                    .ok_or(ProofError::MissingNode { hash })
+            })
+        })
+        .collect()
+}
+
+/// Follow `path` down from the node hashing to `root`, fetching each hashed node with `load`.
+/// Inline children were already decoded with their parent.
+fn walk_path(
+    root: [u8; 32],
+    path: &[u8],
+    mut load: impl FnMut([u8; 32]) -> Result<Node, ProofError>,
+) -> Result<Option<Vec<u8>>, ProofError> {
+    let mut remaining = path;
+    let mut node = Node::Hash(root);
+
+    loop {
+        if let Node::Hash(hash) = node {
+            node = load(hash)?;
+        }
+
+        // A leaf or extension that diverges from the path, or an empty slot on it, proves absence
+        node = match node {
+            Node::Leaf(leaf) if leaf.path.nibbles == remaining => return Ok(Some(leaf.value)),
+            Node::Extension(ext) => match remaining.strip_prefix(ext.path.nibbles.as_slice()) {
+                Some(rest) => {
+                    remaining = rest;
+                    *ext.child
+                }
+                None => return Ok(None),
+            },
+            Node::Branch(mut branch) => match remaining.split_first() {
+                None => return Ok(branch.value),
+                Some((nibble, rest)) => match branch.children[*nibble as usize].take() {
+                    Some(child) => {
+                        remaining = rest;
+                        *child
+                    }
+                    None => return Ok(None),
+                },
+            },
+            Node::Leaf(_) | Node::Hash(_) => return Ok(None),
+        };
+    }
+}
+
+/// Verify that `entries` are exactly the contents of the trie at `root` between `start` and `end`
+/// (both inclusive), given `proof` holding the nodes on both boundary paths.
+///
+/// The boundary paths are rebuilt from the proof, everything they reference inside the range is
+/// cut away, and the entries are inserted in its place: only if nothing is missing, extra or
+/// changed does the result hash back to `root`.
+pub fn verify_range_proof(
+    root: [u8; 32],
+    start: impl AsRef<[u8]>,
+    end: impl AsRef<[u8]>,
+    entries: &[(Vec<u8>, Vec<u8>)],
+    proof: &[Vec<u8>],
+) -> Result<(), ProofError> {
+    let (start, end) = (start.as_ref(), end.as_ref());
+    for (index, (key, _)) in entries.iter().enumerate() {
+        if key.as_slice() < start || key.as_slice() > end {
+            return Err(ProofError::EntryOutOfRange { index });
+        }
+        if index > 0 && entries[index - 1].0 >= *key {
+            return Err(ProofError::UnorderedEntries { index });
+        }
+    }
+
+    let boundaries = Boundaries {
+        start: NibblePath::from_bytes(start).nibbles,
+        end: NibblePath::from_bytes(end).nibbles,
+        nodes: proof
+            .iter()
+            .enumerate()
+            .map(|(index, encoded)| (Keccak256::digest(encoded).into(), (index, encoded)))
+            .collect(),
+    };
+
+    let mut partial = (root != EMPTY_ROOT).then_some(Node::Hash(root));
+    if let Some(node) = &mut partial
+        && boundaries.prune(node, &[])?
+    {
+        partial = None;
+    }
+
+    for (key, value) in entries {
+        let path = NibblePath::from_bytes(key);
+        match &mut partial {
+            None => partial = Some(Node::new_leaf(path, value.clone())),
+            Some(node) => node.insert(path, value.clone()),
+        }
+    }
+
+    let actual = root_hash(partial.as_ref());
+    if actual == root {
+        Ok(())
+    } else {
+        Err(ProofError::RootMismatch { actual })
+    }
+}
+
+/// The nibble paths of a range's boundary keys and the proof nodes on them, by hash.
+struct Boundaries<'a> {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    nodes: HashMap<[u8; 32], (usize, &'a Vec<u8>)>,
+}
+
+impl Boundaries<'_> {
+    /// Cut everything inside the range out of `node`, which sits at `prefix`. Returns true if the
+    /// whole node lies inside the range and should be removed by the caller.
+    fn prune(&self, node: &mut Node, prefix: &[u8]) -> Result<bool, ProofError> {
+        let (start, end) = (self.start.as_slice(), self.end.as_slice());
+        if !start.starts_with(prefix) && !end.starts_with(prefix) {
+            // Off both boundary paths the subtree is wholly inside or wholly outside the range
+            return Ok(prefix > start && prefix < end);
+        }
+
+        if let Node::Hash(hash) = node {
+            let (index, encoded) = self
+                .nodes
+                .get(hash)
+                .ok_or(ProofError::MissingNode { hash: *hash })?;
+            *node =
+                decode_node(encoded).map_err(|_| ProofError::MalformedNode { index: *index })?;
+        }
+
+        match node {
+            Node::Leaf(leaf) => {
+                let key = [prefix, &leaf.path.nibbles].concat();
+                Ok(key.as_slice() >= start && key.as_slice() <= end)
+            }
+            Node::Extension(ext) => {
+                let child_prefix = [prefix, &ext.path.nibbles].concat();
+                self.prune(&mut ext.child, &child_prefix)
+            }
+            Node::Branch(branch) => {
+                if prefix >= start && prefix <= end {
+                    branch.value = None;
+                }
+                for (nibble, slot) in branch.children.iter_mut().enumerate() {
+                    if let Some(child) = slot
+                        && self.prune(child, &[prefix, &[nibble as u8]].concat())?
+                    {
+                        *slot = None;
+                    }
+                }
+                Ok(false)
+            }
+            Node::Hash(_) => unreachable!("resolved above"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    use crate::trie::Trie;
+    use rand::random;
+    use sha3::{Digest, Keccak256};
+
+    fn keccak(bytes: &[u8]) -> [u8; 32] {
+        Keccak256::digest(bytes).into()
+    }
+
+    #[test]
+    fn proof_nodes_chain_from_root() {
+        let mut trie = Trie::new();
+        let keys: Vec<[u8; 32]> = (0..100).map(|_| random::<[u8; 32]>()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+
+        let proof = trie.get_proof(keys[7]).unwrap();
+        assert_eq!(keccak(&proof[0]), trie.root_hash());
+        for pair in proof.windows(2) {
+            let child_hash = keccak(&pair[1]);
+            assert!(pair[0].windows(32).any(|w| w == child_hash));
+        }
+        assert!(proof.last().unwrap().windows(32).any(|w| w == keys[7]));
+    }
+
+    #[test]
+    fn inline_nodes_are_not_listed() {
+        let mut trie = Trie::new();
+        trie.set(b"doe", b"reindeer").unwrap();
+        trie.set(b"dog", b"puppy").unwrap();
+        trie.set(b"dogglesworth", b"cat").unwrap();
+
+        // root extension -> branch, whose leaf for "doe" is embedded in it
+        let proof = trie.get_proof(b"doe").unwrap();
+        assert_eq!(proof.len(), 2);
+        assert!(proof[1].windows(8).any(|w| w == b"reindeer"));
+
+        assert_eq!(
+            Trie::new().get_proof(b"doe").unwrap(),
+            Vec::<Vec<u8>>::new()
+        );
+    }
+
+    #[test]
+    fn db_backed_proof_matches_in_memory() {
+        let keys: Vec<[u8; 32]> = (0..50).map(|_| random::<[u8; 32]>()).collect();
+
+        let mut trie = Trie::from_db(MemoryDB::new());
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().canonicalize_root();
+        let in_memory = trie.get_proof(keys[3]).unwrap();
+
+        let trie = Trie::open(trie.into_db().unwrap(), root_hash);
+        assert_eq!(trie.get_proof(keys[3]).unwrap(), in_memory);
+    }
+
+    #[test]
+    fn verify_every_key() {
+        let mut trie = Trie::new();
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|_| {
+                (0..random::<u8>() % 6)
+                    .map(|_| random::<u8>() % 4)
+                    .collect()
+            })
+            .collect();
+        for key in &keys {
+            trie.set(key, [key.as_slice(), b"-value"].concat()).unwrap();
+        }
+
+        let root = trie.root_hash();
+        for key in &keys {
+            let proof = trie.get_proof(key).unwrap();
+            assert_eq!(verify_proof(root, key, &proof), Ok(trie.get(key).unwrap()));
+        }
+    }
+
+    #[test]
+    fn verify_absent_keys() {
+        let mut trie = Trie::new();
+        for (k, v) in [
+            ("doe", "reindeer"),
+            ("dog", "puppy"),
+            ("dogglesworth", "cat"),
+        ] {
+            trie.set(k, v).unwrap();
+        }
+        let root = trie.root_hash();
+
+        // Diverging extension, empty branch slot, diverging leaf, and a branch without a value
+        for absent in ["cat", "dox", "dogglesworthy", "dogg", "do"] {
+            let proof = trie.get_proof(absent).unwrap();
+            assert_eq!(verify_proof(root, absent, &proof), Ok(None), "{absent}");
+            assert!(matches!(
+                verify_proof(root, absent, &proof[..proof.len() - 1]),
+                Err(ProofError::MissingNode { .. })
+            ));
+        }
+
+        assert_eq!(verify_proof(Trie::new().root_hash(), "dog", &[]), Ok(None));
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let mut trie = Trie::new();
+        let keys: Vec<[u8; 32]> = (0..100).map(|_| random::<[u8; 32]>()).collect();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root = trie.root_hash();
+        let proof = trie.get_proof(keys[0]).unwrap();
+
+        let mut tampered = proof.clone();
+        let last = tampered.last_mut().unwrap();
+        *last.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            verify_proof(root, keys[0], &tampered),
+            Err(ProofError::HashMismatch { index, .. }) if index == proof.len() - 1
+        ));
+
+        let truncated = &proof[..proof.len() - 1];
+        assert!(matches!(
+            verify_proof(root, keys[0], truncated),
+            Err(ProofError::MissingNode { .. })
+        ));
+
+        let mut absent = keys[0];
+        absent[31] ^= 1;
+        let proof = trie.get_proof(absent).unwrap();
+        assert_eq!(verify_proof(root, absent, &proof), Ok(None));
+        assert!(matches!(
+            verify_proof(root, absent, &proof[..proof.len() - 1]),
+            Err(ProofError::MissingNode { .. })
+        ));
+
+        let garbage = vec![0xc1, 0xff, 0x00];
+        assert_eq!(
+            verify_proof(keccak(&garbage), keys[0], &[garbage]),
+            Err(ProofError::MalformedNode { index: 0 })
+        );
+    }
+
+    fn random_trie(count: usize) -> (Trie, Vec<Vec<u8>>) {
+        let mut trie = Trie::new();
+        let mut keys: Vec<Vec<u8>> = (0..count)
+            .map(|_| {
+                (0..1 + random::<u8>() % 4)
+                    .map(|_| random::<u8>())
+                    .collect()
+            })
+            .collect();
+        for key in &keys {
+            trie.set(key, [key.as_slice(), b"-value"].concat()).unwrap();
+        }
+        keys.sort();
+        keys.dedup();
+        (trie, keys)
+    }
+
+    #[test]
+    fn range_proofs_verify() {
+        let (trie, keys) = random_trie(300);
+        let root = trie.root_hash();
+
+        for _ in 0..50 {
+            let (a, b) = (random::<u8>() as usize, random::<u8>() as usize);
+            let (start, end) = (&keys[a.min(b)], &keys[a.max(b)]);
+            let range = trie.get_range_proof(start, end).unwrap();
+            assert_eq!(range.entries.len(), a.max(b) - a.min(b) + 1);
+            assert_eq!(
+                verify_range_proof(root, start, end, &range.entries, &range.proof),
+                Ok(())
+            );
+        }
+
+        // Boundaries that are not keys themselves, and ranges with nothing in them
+        for (start, end) in [
+            (vec![], vec![0xff; 5]),
+            (vec![0x10, 0x00, 0x00, 0x00, 0x00], vec![0x7f]),
+            (vec![0x80, 0x00], vec![0x80, 0x00, 0x00]),
+        ] {
+            let range = trie.get_range_proof(&start, &end).unwrap();
+            assert_eq!(
+                verify_range_proof(root, &start, &end, &range.entries, &range.proof),
+                Ok(())
+            );
+        }
+
+        let range = Trie::new().get_range_proof([0x00], [0xff]).unwrap();
+        assert_eq!(
+            verify_range_proof(EMPTY_ROOT, [0x00], [0xff], &range.entries, &range.proof),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn range_proofs_reject_gaps_and_extras() {
+        let (trie, keys) = random_trie(300);
+        let root = trie.root_hash();
+        let (start, end) = (&keys[40], &keys[120]);
+        let range = trie.get_range_proof(start, end).unwrap();
+
+        let mut gap = range.entries.clone();
+        gap.remove(30);
+        assert!(matches!(
+            verify_range_proof(root, start, end, &gap, &range.proof),
+            Err(ProofError::RootMismatch { .. })
+        ));
+
+        let mut extra = range.entries.clone();
+        let mut key = extra[30].0.clone();
+        while trie.get(&key).unwrap().is_some() {
+            key.push(0x00);
+        }
+        let at = extra.partition_point(|(k, _)| *k < key);
+        extra.insert(at, (key, b"extra".to_vec()));
+        assert!(matches!(
+            verify_range_proof(root, start, end, &extra, &range.proof),
+            Err(ProofError::RootMismatch { .. })
+        ));
+
+        let mut changed = range.entries.clone();
+        changed[10].1 = b"changed".to_vec();
+        assert!(matches!(
+            verify_range_proof(root, start, end, &changed, &range.proof),
+            Err(ProofError::RootMismatch { .. })
+        ));
+
+        // Leaving out an end of the range is a gap too
+        assert!(matches!(
+            verify_range_proof(root, start, end, &range.entries[1..], &range.proof),
+            Err(ProofError::RootMismatch { .. })
+        ));
+
+        let mut unordered = range.entries.clone();
+        unordered.swap(3, 4);
+        assert_eq!(
+            verify_range_proof(root, start, end, &unordered, &range.proof),
+            Err(ProofError::UnorderedEntries { index: 4 })
+        );
+
+        let mut outside = range.entries.clone();
+        outside.push((keys[121].clone(), b"outside".to_vec()));
+        assert_eq!(
+            verify_range_proof(root, start, end, &outside, &range.proof),
+            Err(ProofError::EntryOutOfRange {
+                index: outside.len() - 1
+            })
+        );
+
+        assert!(matches!(
+            verify_range_proof(root, start, end, &range.entries, &range.proof[1..]),
+            Err(ProofError::MissingNode { .. })
+        ));
+    }
+
+    #[test]
+    fn multiproof_shares_nodes_between_keys() {
+        let keys: Vec<[u8; 32]> = (0..500).map(|_| random::<[u8; 32]>()).collect();
+
+        // Proofs from the db reuse the stored encodings instead of rehashing in-memory subtrees
+        let mut trie = Trie::from_db(MemoryDB::new());
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root = trie.commit().unwrap().canonicalize_root();
+        let trie = Trie::open(trie.into_db().unwrap(), root);
+
+        // Half of the queried keys are absent
+        let mut queried = keys[..250].to_vec();
+        queried.extend((0..250).map(|_| random::<[u8; 32]>()));
+        let proof = trie.get_multiproof(&queried).unwrap();
+
+        let separate: usize = queried
+            .iter()
+            .map(|k| trie.get_proof(k).unwrap().len())
+            .sum();
+        assert!(proof.len() < separate / 2);
+        let unique: HashSet<_> = proof.iter().collect();
+        assert_eq!(unique.len(), proof.len());
+
+        let values = verify_multiproof(root, &queried, &proof).unwrap();
+        for (key, value) in queried.iter().zip(values) {
+            assert_eq!(value, trie.get(key).unwrap());
+        }
+
+        // Every node is needed by some key
+        for i in (0..proof.len()).step_by(proof.len() / 10) {
+            let mut partial = proof.clone();
+            partial.remove(i);
+            assert!(matches!(
+                verify_multiproof(root, &queried, &partial),
+                Err(ProofError::MissingNode { .. })
+            ));
+        }
+    }
+}
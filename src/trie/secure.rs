@@ -0,0 +1,215 @@
+use super::{Trie, TrieError};
+#[cfg(feature = "sled")]
+use crate::kv::db::SledDB;
+use crate::kv::db::{DefaultDB, PreimageDB, RootLabel, RootRegistry};
+use crate::kv::storage::NodeRef;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+
+/// Trie keyed by keccak(key) instead of the key itself, the layout Ethereum uses for its account
+/// and storage tries. Hashed keys keep every path 64 nibbles long no matter what the caller's keys
+/// look like.
+pub struct SecureTrie<D: PreimageDB = DefaultDB> {
+    trie: Trie<D>,
+    preimages: Option<HashMap<[u8; 32], Vec<u8>>>, // None unless preimages are recorded
+}
+
+impl Default for SecureTrie<DefaultDB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecureTrie<DefaultDB> {
+    pub fn new() -> Self {
+        Self::from_trie(Trie::new())
+    }
+}
+
+#[cfg(feature = "sled")]
+impl SecureTrie<SledDB> {
+    pub fn with_db(path: impl AsRef<std::path::Path>, tree: &str) -> Result<Self, TrieError> {
+        Ok(Self::from_trie(Trie::with_db(path, tree)?))
+    }
+}
+
+/// The path a key is stored under in a `SecureTrie`, whatever the backend.
+pub fn hash_key(key: impl AsRef<[u8]>) -> [u8; 32] {
+    Keccak256::digest(key.as_ref()).into()
+}
+
+/// An entry of a `SecureTrie`: the hashed key, the original key if it was recorded, and the value.
+pub type SecureEntry = ([u8; 32], Option<Vec<u8>>, Vec<u8>);
+
+impl<D: PreimageDB> SecureTrie<D> {
+    /// An empty trie that commits its nodes and preimages to `db`.
+    pub fn from_db(db: D) -> Self {
+        Self::from_trie(Trie::from_db(db))
+    }
+
+    pub fn open(db: D, root_hash: [u8; 32]) -> Self {
+        Self::from_trie(Trie::open(db, root_hash))
+    }
+
+    fn from_trie(trie: Trie<D>) -> Self {
+        Self {
+            trie,
+            preimages: None,
+        }
+    }
+
+    /// Record the original key behind every hashed path written from now on.
+    /// Preimages are kept in memory and saved alongside the nodes on `commit`.
+    pub fn with_preimages(mut self) -> Self {
+        self.preimages = Some(HashMap::new());
+        self
+    }
+
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), TrieError> {
+        let hashed = hash_key(&key);
+        self.trie.set(hashed, value)?;
+        if let Some(preimages) = &mut self.preimages {
+            preimages.insert(hashed, key.as_ref().to_vec());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, TrieError> {
+        self.trie.get(hash_key(key))
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<bool, TrieError> {
+        self.trie.delete(hash_key(key))
+    }
+
+    /// The original key for a hashed path, if it was recorded.
+    pub fn preimage(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+        if let Some(key) = self.preimages.as_ref().and_then(|p| p.get(hash)) {
+            return Ok(Some(key.clone()));
+        }
+        match self.trie.db() {
+            Some(db) => db.get_preimage(hash).map_err(TrieError::db),
+            None => Ok(None),
+        }
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.trie.root_hash()
+    }
+
+    pub fn commit(&mut self) -> Result<NodeRef, TrieError> {
+        self.write_preimages()?;
+        self.trie.commit()
+    }
+
+    /// Store the pending preimages in one batch. They go before the nodes and are flushed with
+    /// them, so a committed root never has a path whose preimage was lost.
+    fn write_preimages(&mut self) -> Result<(), TrieError> {
+        if let (Some(preimages), Some(db)) = (&mut self.preimages, self.trie.db()) {
+            let batch = preimages.iter().map(|(h, k)| (*h, k.clone())).collect();
+            db.put_preimages(batch).map_err(TrieError::db)?;
+            preimages.clear();
+        }
+        Ok(())
+    }
+
+    /// Every entry in hashed key order, with the original key looked up through `preimage`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<SecureEntry, TrieError>> + '_ {
+        self.trie.iter().map(|entry| {
+            let (hashed, value) = entry?;
+            let hashed: [u8; 32] = hashed.try_into().map_err(|_| TrieError::MalformedNode {
+                reason: "secure trie key is not 32 bytes",
+            })?;
+            Ok((hashed, self.preimage(&hashed)?, value))
+        })
+    }
+
+    /// The underlying trie, keyed by the hashed keys.
+    pub fn trie(&self) -> &Trie<D> {
+        &self.trie
+    }
+}
+
+impl<D: PreimageDB + RootRegistry> SecureTrie<D> {
+    /// `commit`, registering the root under `label` so `with_db` can reopen it.
+    pub fn commit_as(&mut self, label: impl Into<RootLabel>) -> Result<NodeRef, TrieError> {
+        self.write_preimages()?;
+        self.trie.commit_as(label)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    #[cfg(feature = "sled")]
+    use crate::kv::sled_db::tests::{TempStore, reopen};
+
+    #[test]
+    fn keys_are_hashed_before_use() {
+        let mut secure = SecureTrie::new();
+        let mut plain = Trie::new();
+
+        secure.set(b"dog", b"puppy").unwrap();
+        plain.set(hash_key(b"dog"), b"puppy").unwrap();
+
+        assert_eq!(secure.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(plain.get(b"dog").unwrap(), None);
+        assert_eq!(secure.root_hash(), plain.root_hash());
+
+        assert!(secure.delete(b"dog").unwrap());
+        assert_eq!(secure.get(b"dog").unwrap(), None);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn preimages_survive_commit() {
+        let path = TempStore::new("mpt-secure-preimages");
+        let hash = hash_key(b"account");
+
+        let root_hash = {
+            let mut trie = SecureTrie::with_db(&path, "mpt").unwrap().with_preimages();
+            trie.set(b"account", b"balance").unwrap();
+            assert_eq!(trie.preimage(&hash).unwrap(), Some(b"account".to_vec()));
+            trie.commit().unwrap().canonicalize_root()
+        };
+
+        let trie = SecureTrie::open(reopen(|| SledDB::open(&path, "mpt")), root_hash);
+        assert_eq!(trie.get(b"account").unwrap(), Some(b"balance".to_vec()));
+        assert_eq!(trie.preimage(&hash).unwrap(), Some(b"account".to_vec()));
+    }
+
+    #[test]
+    fn iter_shows_recorded_keys() {
+        let mut trie = SecureTrie::from_db(MemoryDB::new()).with_preimages();
+        trie.set(b"dog", b"puppy").unwrap();
+        trie.commit().unwrap();
+        trie.set(b"horse", b"stallion").unwrap();
+
+        let mut entries: Vec<_> = trie.iter().map(Result::unwrap).collect();
+        entries.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(
+            entries,
+            vec![
+                (hash_key(b"dog"), Some(b"dog".to_vec()), b"puppy".to_vec()),
+                (
+                    hash_key(b"horse"),
+                    Some(b"horse".to_vec()),
+                    b"stallion".to_vec()
+                ),
+            ]
+        );
+
+        let mut plain = SecureTrie::new();
+        plain.set(b"dog", b"puppy").unwrap();
+        let entries: Vec<_> = plain.iter().map(Result::unwrap).collect();
+        assert_eq!(entries, vec![(hash_key(b"dog"), None, b"puppy".to_vec())]);
+    }
+
+    #[test]
+    fn preimages_are_not_recorded_by_default() {
+        let mut trie = SecureTrie::new();
+        trie.set(b"account", b"balance").unwrap();
+        assert_eq!(trie.preimage(&hash_key(b"account")).unwrap(), None);
+    }
+}
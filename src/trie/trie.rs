@@ -1,30 +1,113 @@
-use super::{DeleteResult, Key32, NibblePath, Node};
-use crate::kv::db::{HashDB, SledDB};
-use crate::kv::storage::{NodeRef, commit_node, get_value};
+use super::{
+    BatchOp, DeleteResult, NibblePath, Node, RangeProof, TrieCursor, TrieError, TrieIter, TrieView,
+    batch, batch::MaybeSync, proof,
+};
+#[cfg(feature = "sled")]
+use crate::kv::db::SledDB;
+use crate::kv::db::{DefaultDB, HashDB, NodeBatch, RootLabel, RootRegistry};
+use crate::kv::storage::{
+    EMPTY_ROOT, NodeRef, get_value, mark_persisted, resolve_path, resolve_path_for_delete,
+    root_hash, stage_node,
+};
 use crate::utils::display::NodeDisplay;
-use sha3::{Digest, Keccak256};
+use std::ops::{Bound, RangeBounds};
 
-pub struct Trie {
+/// A Merkle Patricia Trie whose nodes are persisted to, and loaded from, any `HashDB` backend.
+/// Errors from the backend are passed through in `TrieError::Db`.
+pub struct Trie<D: HashDB = DefaultDB> {
     root: Option<Node>, // None if empty, otherwise some node (Leaf/Ext/Branch)
-    db: Option<SledDB>,
+    db: Option<D>,
 }
 
-impl Trie {
+impl Default for Trie<DefaultDB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trie<DefaultDB> {
     pub fn new() -> Self {
         Trie {
             root: None,
             db: None,
         }
     }
+}
+
+#[cfg(feature = "sled")]
+impl Trie<SledDB> {
+    /// Open the store at `path` at the root committed last, or empty if nothing was committed.
+    pub fn with_db(path: impl AsRef<std::path::Path>, tree: &str) -> Result<Self, TrieError> {
+        let db = SledDB::open(path, tree).map_err(TrieError::db)?;
+        Self::open_latest(db)
+    }
+
+    /// Open the store at `path` at the root registered under `label`.
+    pub fn with_db_at(
+        path: impl AsRef<std::path::Path>,
+        tree: &str,
+        label: impl Into<RootLabel>,
+    ) -> Result<Self, TrieError> {
+        let db = SledDB::open(path, tree).map_err(TrieError::db)?;
+        Self::open_at(db, label)
+    }
+}
+
+impl<D: RootRegistry> Trie<D> {
+    /// Open `db` at the root committed last, or empty if nothing was committed.
+    pub fn open_latest(db: D) -> Result<Self, TrieError> {
+        let latest = db.latest_root().map_err(TrieError::db)?;
+        Ok(match latest {
+            Some(root) => Self::open(db, root),
+            None => Self::from_db(db),
+        })
+    }
+
+    /// Open `db` at the root registered under `label`.
+    pub fn open_at(db: D, label: impl Into<RootLabel>) -> Result<Self, TrieError> {
+        let label = label.into();
+        match db.get_root(&label).map_err(TrieError::db)? {
+            Some(root) => Ok(Self::open(db, root)),
+            None => Err(TrieError::UnknownRoot(label)),
+        }
+    }
+
+    /// A read-only view of the trie at the root registered under `label`.
+    pub fn view_at_label(&self, label: impl Into<RootLabel>) -> Result<TrieView<'_, D>, TrieError> {
+        let label = label.into();
+        let db = self.db.as_ref().ok_or(TrieError::NoDb)?;
+        match db.get_root(&label).map_err(TrieError::db)? {
+            Some(root) => Ok(TrieView::new(db, root)),
+            None => Err(TrieError::UnknownRoot(label)),
+        }
+    }
+
+    /// `commit`, registering the root under `label` in the same write so the trie can be
+    /// reopened at it.
+    pub fn commit_as(&mut self, label: impl Into<RootLabel>) -> Result<NodeRef, TrieError> {
+        let label = label.into();
+        self.commit_with(|db, batch, root| db.commit_batch(batch, label, root))
+    }
+}
 
-    pub fn with_db(path: impl AsRef<std::path::Path>, tree: &str) -> Self {
-        let db = SledDB::open(path, tree).expect("open sled");
+impl<D: HashDB> Trie<D> {
+    /// An empty trie that commits its nodes to `db`.
+    pub fn from_db(db: D) -> Self {
         Self {
             root: None,
             db: Some(db),
         }
     }
 
+    /// Open a persisted trie at `root_hash`. Nodes are loaded from `db` only when a path needs
+    /// them.
+    pub fn open(db: D, root_hash: [u8; 32]) -> Self {
+        Self {
+            root: (root_hash != EMPTY_ROOT).then_some(Node::Hash(root_hash)),
+            db: Some(db),
+        }
+    }
+
     pub fn print_tree(&self) {
         match &self.root {
             None => println!("Trie is empty"),
@@ -32,96 +115,199 @@ impl Trie {
         }
     }
 
-    pub fn commit(&mut self) -> NodeRef {
-        let db = self.db.as_mut().unwrap();
+    /// Write every node changed since the last commit to the db in one batch, and flush it.
+    pub fn commit(&mut self) -> Result<NodeRef, TrieError> {
+        self.commit_with(|db, batch, root| db.write_commit(batch, root))
+    }
 
-        let root = match &self.root {
-            None => NodeRef::Inline(vec![]),
-            Some(n) => commit_node(db, n),
+    /// Stage the nodes to commit and hand them to `write` with the root hash. The in-memory
+    /// nodes count as stored only once `write` succeeds, so a failed commit can be retried.
+    fn commit_with(
+        &mut self,
+        write: impl FnOnce(&D, NodeBatch, [u8; 32]) -> Result<(), D::Error>,
+    ) -> Result<NodeRef, TrieError> {
+        let db = self.db.as_ref().ok_or(TrieError::NoDb)?;
+
+        let (root, mut batch) = match &self.root {
+            None => (NodeRef::Hash(EMPTY_ROOT), vec![]),
+            Some(n) => stage_node(n),
         };
 
-        let root_key = Keccak256::digest(b"__ROOT__").into();
+        // A root under 32 bytes is never hashed by its parent, store it so `open` can find it
+        if let NodeRef::Inline(bytes) = &root {
+            batch.push((root.canonicalize_root(), bytes.clone()));
+        }
 
-        println!("Root Key: {:x?}", root_key);
+        write(db, batch, root.canonicalize_root()).map_err(TrieError::db)?;
+        if let Some(n) = &self.root {
+            mark_persisted(n);
+        }
+        db.flush().map_err(TrieError::db)?;
 
-        let _ = db.put(root_key, root.canonicalize_root().to_vec());
+        Ok(root)
+    }
 
-        root
+    /// Ethereum-compatible root hash of the in-memory trie. Needs no database and writes nothing.
+    pub fn root_hash(&self) -> [u8; 32] {
+        root_hash(self.root.as_ref())
     }
 
     pub fn root(&self) -> Option<&Node> {
         self.root.as_ref()
     }
 
-    pub fn set(&mut self, key: Key32, value: impl AsRef<[u8]>) {
+    /// Every `(key, value)` pair in lexicographic key order.
+    pub fn iter(&self) -> TrieIter<'_, D> {
+        TrieIter::new(self.db.as_ref(), self.root.as_ref())
+    }
+
+    /// Every `(key, value)` pair in reverse key order.
+    pub fn iter_rev(&self) -> TrieIter<'_, D> {
+        self.iter().reversed()
+    }
+
+    /// The pairs whose keys fall within `range`, in key order.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> TrieIter<'_, D> {
+        let lower = range.start_bound().map(AsRef::as_ref);
+        let upper = range.end_bound().map(AsRef::as_ref);
+        self.iter().with_range(lower, upper)
+    }
+
+    /// The pairs whose keys fall within `range`, in reverse key order.
+    pub fn range_rev<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> TrieIter<'_, D> {
+        self.range(range).reversed()
+    }
+
+    /// The pairs whose key paths start with `prefix`, in key order. The prefix may end mid-byte.
+    pub fn iter_prefix(&self, prefix: &NibblePath) -> TrieIter<'_, D> {
+        self.iter().with_prefix(prefix)
+    }
+
+    /// Merkle proof for `key`: the RLP-encoded nodes on its path from the root, as returned by
+    /// `eth_getProof`. Works on in-memory tries and on tries loaded from the db. For an absent
+    /// key the path ends at the node that shows it diverges, which proves non-membership.
+    pub fn get_proof(&self, key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>, TrieError> {
+        proof::get_proof(self.db.as_ref(), self.root.as_ref(), key.as_ref())
+    }
+
+    /// Proof nodes for all of `keys`, present or absent, with each node listed once.
+    pub fn get_multiproof<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Vec<u8>>, TrieError> {
+        proof::get_multiproof(self.db.as_ref(), self.root.as_ref(), keys)
+    }
+
+    /// Every entry with a key between `start` and `end` (both inclusive), with the proof nodes
+    /// for both boundaries, so a peer can check it against the root with `verify_range_proof`.
+    pub fn get_range_proof(
+        &self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+    ) -> Result<RangeProof, TrieError> {
+        let (start, end) = (start.as_ref(), end.as_ref());
+        let entries = self
+            .range::<&[u8]>((Bound::Included(start), Bound::Included(end)))
+            .collect::<Result<_, _>>()?;
+        let proof = self.get_multiproof(&[start, end])?;
+        Ok(RangeProof { entries, proof })
+    }
+
+    /// A cursor for seeking, stepping through and editing entries in place.
+    pub fn cursor(&mut self) -> TrieCursor<'_, D> {
+        TrieCursor::new(self)
+    }
+
+    pub(crate) fn db(&self) -> Option<&D> {
+        self.db.as_ref()
+    }
+
+    /// Give back the backend, e.g. to open it again at another root.
+    pub fn into_db(self) -> Option<D> {
+        self.db
+    }
+
+    /// Set `key` to `value`. An empty value deletes the key, as in Ethereum: it encodes the same
+    /// as no value, so it could not be told apart once committed.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), TrieError> {
+        if value.as_ref().is_empty() {
+            return self.delete(key).map(drop);
+        }
         let v = value.as_ref().to_vec();
+        let path = NibblePath::from_bytes(key.as_ref());
         match &mut self.root {
             None => {
-                let path = NibblePath::from(key);
                 //Trie is empty, so create a new leaf node
                 self.root = Some(Node::new_leaf(path, v));
             }
             Some(root) => {
-                root.insert(key.into(), v);
+                resolve_path(self.db.as_ref(), root, &path.nibbles)?;
+                root.insert(path, v);
             }
         }
+        Ok(())
+    }
 
-        println!("Node in memory: {:x?}", self.root);
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, TrieError> {
+        let path = NibblePath::from_bytes(key.as_ref());
+        match &self.root {
+            None => Ok(None),
+            Some(root) => get_value(self.db.as_ref(), root, &path.nibbles),
+        }
     }
 
-    pub fn get(&self, key: Key32) -> Option<Vec<u8>> {
-        //Do we have a db?
-        if let Some(db) = &self.db {
-            let root_key: [u8; 32] = Keccak256::digest(b"__ROOT__").into();
-            let mut root_hash = db.get(&root_key);
+    /// The value `key` had in the committed trie with root `root_hash`. Reads the db only, so
+    /// the in-memory trie and its uncommitted changes are left alone.
+    pub fn get_at(
+        &self,
+        root_hash: [u8; 32],
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Vec<u8>>, TrieError> {
+        self.view_at(root_hash)?.get(key)
+    }
 
-            //here i need to say that the root hash is a [u8; 32]
+    /// A read-only view of the committed trie with root `root_hash`.
+    pub fn view_at(&self, root_hash: [u8; 32]) -> Result<TrieView<'_, D>, TrieError> {
+        let db = self.db.as_ref().ok_or(TrieError::NoDb)?;
+        Ok(TrieView::new(db, root_hash))
+    }
 
-            if let Ok(Some(root_hash)) = root_hash {
-                let root_hash: [u8; 32] = root_hash
-                    .try_into()
-                    .expect("Root hash should always be 32 bytes");
-                get_value(db, &key.0, &root_hash);
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<bool, TrieError> {
+        let path = NibblePath::from_bytes(key.as_ref());
+        let Some(root) = &mut self.root else {
+            return Ok(false); // Key doesn't exist in empty trie
+        };
+        resolve_path_for_delete(self.db.as_ref(), root, &path.nibbles)?;
+
+        Ok(match root.delete(path) {
+            DeleteResult::Deleted => true,
+            DeleteResult::NotFound => false,
+            DeleteResult::DeletedAndReplace(new_root) => {
+                *root = new_root;
+                true
             }
-        }
-
-        //If we don't have a db, we just get the root from the trie
-        match &self.root {
-            None => None,
-            Some(root) => root.get(key.into()).cloned(),
-        }
+            DeleteResult::DeletedAndRemove => {
+                self.root = None;
+                true
+            }
+        })
     }
 
-    pub fn delete(&mut self, key: Key32) -> bool {
-        match &mut self.root {
-            None => false, // Key doesn't exist in empty trie
-            Some(root) => {
-                //if the root is a leaf node and the key matches the path, set the root to None
-                if let Node::Leaf(leaf) = root {
-                    if leaf.path == key.into() {
-                        self.root = None;
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    match root.delete(key.into()) {
-                        DeleteResult::Deleted => true,
-                        DeleteResult::NotFound => false,
-                        DeleteResult::DeletedAndReplace(new_root) => {
-                            *root = new_root;
-                            true
-                        }
-                    }
-                }
-            }
-        }
+    /// Apply many sets and deletes at once, with the same result as applying them in order.
+    /// Keys are grouped by their first nibble and each group modifies its own subtree of the
+    /// root, on its own thread with the `parallel` feature.
+    pub fn apply_batch(&mut self, ops: impl IntoIterator<Item = BatchOp>) -> Result<(), TrieError>
+    where
+        D: MaybeSync,
+    {
+        batch::apply_batch(self.db.as_ref(), &mut self.root, ops.into_iter().collect())
     }
 }
 
 #[cfg(test)]
 mod unit_tests {
     use super::*;
+    use crate::kv::db::MemoryDB;
+    #[cfg(feature = "sled")]
+    use crate::kv::sled_db::tests::{TempStore, reopen};
+    use crate::trie::Key32;
     use rand::random;
 
     #[test]
@@ -130,12 +316,87 @@ mod unit_tests {
         assert_eq!(trie.root(), None);
     }
 
+    #[test]
+    fn empty_trie_has_canonical_empty_root() {
+        let trie = Trie::new();
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+        );
+    }
+
+    #[test]
+    fn root_hash_matches_commit() {
+        let mut trie = Trie::from_db(MemoryDB::new());
+        trie.set(Key32(*b"123456abcdefghijklmnopqrstuvwxyz"), b"val1")
+            .unwrap();
+        trie.set(Key32(*b"123456zyxwvutsrqponmlkjihgfedcba"), b"val2")
+            .unwrap();
+
+        let root_hash = trie.root_hash();
+        assert_eq!(trie.commit().unwrap().canonicalize_root(), root_hash);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn open_at_root_resolves_lazily() {
+        let path = TempStore::new("mpt-open-at-root");
+        let keys = [
+            Key32(*b"j23456abcdefghijklmnopqrstuvwxyz"),
+            Key32(*b"523456abcdefghijklmnopqrstuvwxyz"),
+            Key32(*b"523456zyxwvutsrqponmlkjihgfedcba"),
+            Key32(*b"523abcdefghijklmnopqrstuvwxyz123"),
+        ];
+
+        let mut expected = Trie::new();
+        let root_hash = {
+            let mut trie = Trie::with_db(&path, "mpt").unwrap();
+            for key in keys {
+                trie.set(key, b"hello").unwrap();
+                expected.set(key, b"hello").unwrap();
+            }
+            trie.commit().unwrap().canonicalize_root()
+        };
+
+        let mut trie = Trie::open(reopen(|| SledDB::open(&path, "mpt")), root_hash);
+        assert!(matches!(trie.root(), Some(Node::Hash(_))));
+        assert_eq!(trie.get(keys[2]).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(trie.root_hash(), root_hash);
+
+        // Deleting forces the untouched sibling to be loaded so the branch can collapse
+        trie.delete(keys[1]).unwrap();
+        expected.delete(keys[1]).unwrap();
+        trie.set(keys[0], b"world").unwrap();
+        expected.set(keys[0], b"world").unwrap();
+
+        assert_eq!(trie.get(keys[0]).unwrap(), Some(b"world".to_vec()));
+        assert_eq!(trie.get(keys[1]).unwrap(), None);
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn iter_loads_nodes_from_db() {
+        let mut keys: Vec<[u8; 32]> = (0..50).map(|_| random::<[u8; 32]>()).collect();
+
+        let mut trie = Trie::from_db(MemoryDB::new());
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().canonicalize_root();
+
+        let trie = Trie::open(trie.into_db().unwrap(), root_hash);
+        keys.sort();
+        let entries: Vec<_> = trie.iter().map(Result::unwrap).collect();
+        let expected: Vec<_> = keys.iter().map(|k| (k.to_vec(), k.to_vec())).collect();
+        assert_eq!(entries, expected);
+    }
+
     #[test]
     fn single_insert_creates_leaf_root() {
         let mut trie = Trie::new();
         let key = Key32(random::<[u8; 32]>());
 
-        trie.set(key, b"hello");
+        trie.set(key, b"hello").unwrap();
 
         assert!(matches!(trie.root(), Some(Node::Leaf(_))));
 
@@ -157,8 +418,8 @@ mod unit_tests {
             key2 = Key32(random::<[u8; 32]>());
         }
 
-        trie.set(key1, b"hello");
-        trie.set(key2, b"world");
+        trie.set(key1, b"hello").unwrap();
+        trie.set(key2, b"world").unwrap();
 
         // Root should be a branch
         assert!(matches!(trie.root(), Some(Node::Branch(_))));
@@ -179,8 +440,8 @@ mod unit_tests {
         key1[6..].copy_from_slice(b"abcdefghijklmnopqrstuvwxyz");
         key2[6..].copy_from_slice(b"zyxwvutsrqponmlkjihgfedcba");
 
-        trie.set(Key32(key1), b"hello");
-        trie.set(Key32(key2), b"world");
+        trie.set(Key32(key1), b"hello").unwrap();
+        trie.set(Key32(key2), b"world").unwrap();
 
         // Root should be an extension
         assert!(matches!(trie.root(), Some(Node::Extension(_))));
@@ -202,9 +463,9 @@ mod unit_tests {
         let key2 = Key32(*b"523456abcdefghijklmnopqrstuvwxyz");
         let key3 = Key32(*b"523456zyxwvutsrqponmlkjihgfedcba");
 
-        trie.set(key1, b"val1");
-        trie.set(key2, b"val2");
-        trie.set(key3, b"val3");
+        trie.set(key1, b"val1").unwrap();
+        trie.set(key2, b"val2").unwrap();
+        trie.set(key3, b"val3").unwrap();
 
         // Verify root is a branch (j vs 5)
         assert!(matches!(trie.root(), Some(Node::Branch(_))));
@@ -220,18 +481,212 @@ mod unit_tests {
         }
     }
 
+    #[cfg(feature = "sled")]
+    #[test]
+    fn registered_roots_survive_restart() {
+        let path = TempStore::new("mpt-registry-restart");
+
+        {
+            let mut trie = Trie::with_db(&path, "mpt").unwrap();
+            assert!(trie.root().is_none());
+            trie.set(b"dog", b"puppy").unwrap();
+            trie.commit_as(1).unwrap();
+            trie.set(b"dog", b"hound").unwrap();
+            trie.set(b"horse", b"stallion").unwrap();
+            trie.commit_as(2).unwrap();
+        }
+
+        let latest = reopen(|| Trie::with_db(&path, "mpt"));
+        assert_eq!(latest.get(b"dog").unwrap(), Some(b"hound".to_vec()));
+        drop(latest);
+
+        let first = reopen(|| Trie::with_db_at(&path, "mpt", 1));
+        assert_eq!(first.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(first.get(b"horse").unwrap(), None);
+
+        let db = first.into_db().unwrap();
+        assert!(matches!(
+            Trie::open_at(db, "genesis"),
+            Err(TrieError::UnknownRoot(RootLabel::Name(name))) if name == "genesis"
+        ));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn plain_commits_survive_restart() {
+        let path = TempStore::new("mpt-plain-restart");
+
+        {
+            let mut trie = Trie::with_db(&path, "mpt").unwrap();
+            trie.set(b"dog", b"puppy").unwrap();
+            trie.commit().unwrap();
+        }
+        let mut trie = reopen(|| Trie::with_db(&path, "mpt"));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+
+        // A plain commit after a registered one is still the one reopened
+        trie.set(b"dog", b"hound").unwrap();
+        trie.commit_as(1).unwrap();
+        trie.set(b"dog", b"mutt").unwrap();
+        trie.commit().unwrap();
+        drop(trie);
+
+        let trie = reopen(|| Trie::with_db(&path, "mpt"));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"mutt".to_vec()));
+        drop(trie);
+        let trie = reopen(|| Trie::with_db_at(&path, "mpt", 1));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"hound".to_vec()));
+    }
+
+    #[test]
+    fn empty_values_delete() {
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let mut expected = Trie::new();
+        for key in [&b"a"[..], b"ab", b"abc", b"b"] {
+            trie.set(key, key).unwrap();
+            expected.set(key, key).unwrap();
+        }
+        trie.set(b"a", b"").unwrap();
+        trie.set(b"abc", []).unwrap();
+        trie.set(b"z", b"").unwrap();
+        trie.apply_batch([BatchOp::Set(b"b".to_vec(), vec![])])
+            .unwrap();
+        for key in [&b"a"[..], b"abc", b"b"] {
+            expected.delete(key).unwrap();
+        }
+        assert_eq!(trie.get(b"a").unwrap(), None);
+        assert_eq!(trie.root_hash(), expected.root_hash());
+
+        let root = trie.commit().unwrap().canonicalize_root();
+        let trie = Trie::open(trie.into_db().unwrap(), root);
+        assert_eq!(trie.get(b"a").unwrap(), None);
+        assert_eq!(trie.get(b"ab").unwrap(), Some(b"ab".to_vec()));
+        assert_eq!(
+            trie.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            expected.iter().collect::<Result<Vec<_>, _>>().unwrap()
+        );
+    }
+
     #[test]
     fn commit_trie_with_db() {
-        let mut trie = Trie::with_db("db", "mpt");
-        let key = String::from("hello").into();
-        trie.set(key, b"world");
-        let root_hash = trie.commit();
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let key: Key32 = String::from("hello").into();
+        trie.set(key, b"world").unwrap();
+        let root_hash = trie.commit().unwrap();
         println!("root_hash: {}", root_hash);
     }
 
+    #[test]
+    fn cached_hashes_follow_sets_and_deletes() {
+        let mut trie = Trie::new();
+        let mut model = std::collections::BTreeMap::new();
+
+        for _ in 0..300 {
+            let key: Vec<u8> = (0..1 + random::<u8>() % 3)
+                .map(|_| random::<u8>() % 8)
+                .collect();
+            if random::<u8>().is_multiple_of(3) {
+                trie.delete(&key).unwrap();
+                model.remove(&key);
+            } else {
+                let value = vec![random::<u8>(); 1 + random::<u8>() as usize % 40];
+                trie.set(&key, &value).unwrap();
+                model.insert(key, value);
+            }
+
+            let mut fresh = Trie::new();
+            for (key, value) in &model {
+                fresh.set(key, value).unwrap();
+            }
+            assert_eq!(trie.root_hash(), fresh.root_hash());
+        }
+    }
+
+    #[test]
+    fn set_only_clears_cache_on_its_path() {
+        let mut trie = Trie::new();
+        for _ in 0..200 {
+            let key: [u8; 32] = random();
+            trie.set(key, key).unwrap();
+        }
+        trie.root_hash();
+
+        let mut key: [u8; 32] = random();
+        key[0] = 0x3a;
+        trie.set(key, b"changed").unwrap();
+
+        let Some(Node::Branch(root)) = trie.root() else {
+            panic!("200 random keys leave a branch at the root")
+        };
+        assert!(root.cache.get().is_none());
+        for (nibble, child) in root.children.iter().enumerate() {
+            let child = child.as_ref().expect("every slot is taken");
+            let cached = child.cache().unwrap().get().is_some();
+            assert_eq!(cached, nibble != 0x3);
+        }
+    }
+
+    /// A backend that refuses every write.
+    #[derive(Default)]
+    struct ReadOnly(MemoryDB);
+
+    #[derive(Debug, PartialEq)]
+    struct Refused;
+
+    impl std::fmt::Display for Refused {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "read only")
+        }
+    }
+
+    impl std::error::Error for Refused {}
+
+    impl HashDB for ReadOnly {
+        type Error = Refused;
+
+        fn get(&self, key: &[u8; 32]) -> Result<Option<Vec<u8>>, Refused> {
+            let Ok(node) = self.0.get(key);
+            Ok(node)
+        }
+
+        fn put(&self, _key: [u8; 32], _value: Vec<u8>) -> Result<(), Refused> {
+            Err(Refused)
+        }
+
+        fn flush(&self) -> Result<(), Refused> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn runs_over_any_backend() {
+        let keys: Vec<[u8; 32]> = (0..100).map(|_| random()).collect();
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let mut expected = Trie::new();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+            expected.set(key, key).unwrap();
+        }
+        let root = trie.commit().unwrap().canonicalize_root();
+        assert_eq!(root, expected.root_hash());
+
+        let trie = Trie::open(trie.into_db().unwrap(), root);
+        for key in &keys {
+            assert_eq!(trie.get(key).unwrap(), Some(key.to_vec()));
+        }
+
+        // The backend's own error comes back out of the trie
+        let mut trie = Trie::from_db(ReadOnly::default());
+        trie.set(keys[0], [0; 32]).unwrap();
+        let Err(TrieError::Db(err)) = trie.commit() else {
+            panic!("the write is refused")
+        };
+        assert_eq!(err.downcast_ref::<Refused>(), Some(&Refused));
+    }
+
     // #[test]
     // fn commit_trie_with_db_and_complex_structure() {
-    //     let mut trie = Trie::with_db("db", "mpt");
+    //     let mut trie = Trie::with_db("db", "mpt").unwrap();
 
     //     let keys = [
     //         Key32(*b"j23456abcdefghijklmnopqrstuvwxyz"),
@@ -242,10 +697,10 @@ mod unit_tests {
     //     ];
 
     //     for key in keys {
-    //         trie.set(key, b"hello");
+    //         trie.set(key, b"hello").unwrap();
     //     }
 
-    //     let root_hash = trie.commit();
+    //     let root_hash = trie.commit().unwrap();
 
     //     println!("root_hash: {}", root_hash);
     // }
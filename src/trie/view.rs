@@ -0,0 +1,113 @@
+use super::{NibblePath, Node, TrieError, TrieIter, proof};
+use crate::kv::db::{DefaultDB, HashDB};
+use crate::kv::storage::{EMPTY_ROOT, get_value};
+use std::ops::RangeBounds;
+
+/// Read-only access to a trie as it was committed at some root. Nodes are loaded from the db on
+/// every read and never kept, so a view costs nothing to open and never sees uncommitted changes.
+pub struct TrieView<'a, D: HashDB = DefaultDB> {
+    db: &'a D,
+    root: Option<Node>, // None for the empty root, otherwise the unresolved root hash
+}
+
+impl<'a, D: HashDB> TrieView<'a, D> {
+    pub fn new(db: &'a D, root_hash: [u8; 32]) -> Self {
+        Self {
+            db,
+            root: (root_hash != EMPTY_ROOT).then_some(Node::Hash(root_hash)),
+        }
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        match &self.root {
+            Some(Node::Hash(hash)) => *hash,
+            _ => EMPTY_ROOT,
+        }
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, TrieError> {
+        let path = NibblePath::from_bytes(key.as_ref());
+        match &self.root {
+            None => Ok(None),
+            Some(root) => get_value(Some(self.db), root, &path.nibbles),
+        }
+    }
+
+    /// Every `(key, value)` pair in lexicographic key order.
+    pub fn iter(&self) -> TrieIter<'_, D> {
+        TrieIter::new(Some(self.db), self.root.as_ref())
+    }
+
+    /// Every `(key, value)` pair in reverse key order.
+    pub fn iter_rev(&self) -> TrieIter<'_, D> {
+        self.iter().reversed()
+    }
+
+    /// The pairs whose keys fall within `range`, in key order.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> TrieIter<'_, D> {
+        let lower = range.start_bound().map(AsRef::as_ref);
+        let upper = range.end_bound().map(AsRef::as_ref);
+        self.iter().with_range(lower, upper)
+    }
+
+    /// The pairs whose key paths start with `prefix`, in key order.
+    pub fn iter_prefix(&self, prefix: &NibblePath) -> TrieIter<'_, D> {
+        self.iter().with_prefix(prefix)
+    }
+
+    /// Merkle proof for `key` against this view's root.
+    pub fn get_proof(&self, key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>, TrieError> {
+        proof::get_proof(Some(self.db), self.root.as_ref(), key.as_ref())
+    }
+
+    /// Proof nodes for all of `keys` against this view's root, with each node listed once.
+    pub fn get_multiproof<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Vec<u8>>, TrieError> {
+        proof::get_multiproof(Some(self.db), self.root.as_ref(), keys)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::kv::db::MemoryDB;
+    use crate::trie::{Trie, verify_proof};
+
+    #[test]
+    fn reads_past_roots_without_touching_the_trie() {
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let mut roots = vec![];
+        for block in 0..4u64 {
+            for key in 0..20u8 {
+                trie.set([key], block.to_be_bytes()).unwrap();
+            }
+            trie.set([100 + block as u8], b"new").unwrap();
+            roots.push(trie.commit_as(block).unwrap().canonicalize_root());
+        }
+        trie.set([7], b"uncommitted").unwrap();
+        let in_memory = trie.root_hash();
+
+        assert_eq!(
+            trie.get_at(roots[1], [7]).unwrap(),
+            Some(1u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(trie.get_at(roots[1], [102]).unwrap(), None);
+        assert_eq!(trie.get_at(EMPTY_ROOT, [7]).unwrap(), None);
+        assert!(matches!(
+            trie.get_at(in_memory, [7]),
+            Err(TrieError::MissingNode { .. })
+        ));
+
+        let view = trie.view_at_label(2).unwrap();
+        assert_eq!(view.root_hash(), roots[2]);
+        assert_eq!(view.iter().count(), 23);
+        assert_eq!(view.range([100u8]..).count(), 3);
+        let proof = view.get_proof([5]).unwrap();
+        assert_eq!(
+            verify_proof(roots[2], [5], &proof).unwrap(),
+            Some(2u64.to_be_bytes().to_vec())
+        );
+
+        assert_eq!(trie.get([7]).unwrap(), Some(b"uncommitted".to_vec()));
+        assert_eq!(trie.root_hash(), in_memory);
+    }
+}
@@ -41,6 +41,7 @@ impl NodeDisplay for Node {
                 }
                 Ok(())
             }
+            Node::Hash(h) => writeln!(f, "{}Hash: 0x{}", prefix, hex::encode(h)),
         }
     }
 
@@ -99,6 +100,9 @@ impl NodeDisplay for Node {
                     child.print_tree_recursive(&child_prefix, true);
                 }
             }
+            Node::Hash(h) => {
+                println!("{}{}Hash(0x{})", prefix, connector, hex::encode(&h[..4]));
+            }
         }
     }
 }
@@ -1,18 +1,20 @@
 #[cfg(test)]
-
 // =====================================================================
 // API Tests - Test functionality through public interface
 // =====================================================================
 mod api_tests {
 
-    use merkle_patricia_trie::trie::{Key32, Trie};
+    use merkle_patricia_trie::kv::db::MemoryDB;
+    use merkle_patricia_trie::trie::{Key32, NibblePath, Node, Trie, TrieError};
     use rand::random;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::ops::Bound;
 
     #[test]
     fn empty_trie_returns_none() {
         let trie = Trie::new();
         let key = Key32(random::<[u8; 32]>());
-        assert_eq!(trie.get(key), None);
+        assert_eq!(trie.get(key).unwrap(), None);
     }
 
     #[test]
@@ -20,8 +22,8 @@ mod api_tests {
         let mut trie = Trie::new();
         let key = Key32(random::<[u8; 32]>());
 
-        trie.set(key, b"hello");
-        assert_eq!(trie.get(key), Some(b"hello".to_vec()));
+        trie.set(key, b"hello").unwrap();
+        assert_eq!(trie.get(key).unwrap(), Some(b"hello".to_vec()));
     }
 
     #[test]
@@ -29,9 +31,9 @@ mod api_tests {
         let mut trie = Trie::new();
         let key = Key32(random::<[u8; 32]>());
 
-        trie.set(key, b"hello");
-        assert_eq!(trie.delete(key), true);
-        assert_eq!(trie.get(key), None);
+        trie.set(key, b"hello").unwrap();
+        assert!(trie.delete(key).unwrap());
+        assert_eq!(trie.get(key).unwrap(), None);
     }
 
     #[test]
@@ -39,10 +41,10 @@ mod api_tests {
         let mut trie = Trie::new();
         let key = Key32(random::<[u8; 32]>());
 
-        trie.set(key, b"hello");
-        trie.set(key, b"world");
+        trie.set(key, b"hello").unwrap();
+        trie.set(key, b"world").unwrap();
 
-        assert_eq!(trie.get(key), Some(b"world".to_vec()));
+        assert_eq!(trie.get(key).unwrap(), Some(b"world".to_vec()));
     }
 
     #[test]
@@ -57,11 +59,11 @@ mod api_tests {
             key2 = Key32(random::<[u8; 32]>());
         }
 
-        trie.set(key1, b"value1");
-        trie.set(key2, b"value2");
+        trie.set(key1, b"value1").unwrap();
+        trie.set(key2, b"value2").unwrap();
 
-        assert_eq!(trie.get(key1), Some(b"value1".to_vec()));
-        assert_eq!(trie.get(key2), Some(b"value2".to_vec()));
+        assert_eq!(trie.get(key1).unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(trie.get(key2).unwrap(), Some(b"value2".to_vec()));
     }
 
     #[test]
@@ -71,11 +73,11 @@ mod api_tests {
         let key1 = Key32(*b"123456abcdefghijklmnopqrstuvwxyz");
         let key2 = Key32(*b"123456zyxwvutsrqponmlkjihgfedcba");
 
-        trie.set(key1, b"value1");
-        trie.set(key2, b"value2");
+        trie.set(key1, b"value1").unwrap();
+        trie.set(key2, b"value2").unwrap();
 
-        assert_eq!(trie.get(key1), Some(b"value1".to_vec()));
-        assert_eq!(trie.get(key2), Some(b"value2".to_vec()));
+        assert_eq!(trie.get(key1).unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(trie.get(key2).unwrap(), Some(b"value2".to_vec()));
     }
 
     #[test]
@@ -84,14 +86,14 @@ mod api_tests {
         let key1 = Key32(*b"123456abcdefghijklmnopqrstuvwxyz");
         let key2 = Key32(*b"k23456zyxwvutsrqponmlkjihgfedcba");
 
-        trie.set(key1, b"value1");
-        trie.set(key2, b"value2");
+        trie.set(key1, b"value1").unwrap();
+        trie.set(key2, b"value2").unwrap();
 
         trie.print_tree();
 
         println!("\nDeleting key1\n");
 
-        trie.delete(key1);
+        trie.delete(key1).unwrap();
 
         trie.print_tree();
     }
@@ -116,13 +118,13 @@ mod api_tests {
 
         // Insert all keys
         for (key, value) in keys.iter().zip(values.iter()) {
-            trie.set(*key, value.to_vec());
+            trie.set(*key, value).unwrap();
             trie_versions.push(trie.root().cloned()); // Clone the Option<&Node> to Option<Node>
         }
 
         // Delete in reverse order and verify trie matches previous versions
         for i in (0..keys.len()).rev() {
-            trie.delete(keys[i]);
+            trie.delete(keys[i]).unwrap();
             assert_eq!(
                 trie.root(),
                 trie_versions[i].as_ref(),
@@ -144,14 +146,14 @@ mod api_tests {
         ];
 
         for (i, key) in keys.iter().enumerate() {
-            trie.set(*key, format!("val{}", i).as_bytes().to_vec());
+            trie.set(*key, format!("val{}", i).as_bytes()).unwrap();
         }
 
         // Delete middle key - should keep extension but modify branch
-        assert!(trie.delete(keys[1]));
-        assert_eq!(trie.get(keys[0]), Some(b"val0".to_vec()));
-        assert_eq!(trie.get(keys[1]), None);
-        assert_eq!(trie.get(keys[2]), Some(b"val2".to_vec()));
+        assert!(trie.delete(keys[1]).unwrap());
+        assert_eq!(trie.get(keys[0]).unwrap(), Some(b"val0".to_vec()));
+        assert_eq!(trie.get(keys[1]).unwrap(), None);
+        assert_eq!(trie.get(keys[2]).unwrap(), Some(b"val2".to_vec()));
     }
 
     #[test]
@@ -163,20 +165,20 @@ mod api_tests {
         let key2 = Key32(*b"common22222222222222222222222222");
         let key3 = Key32(*b"common23333333333333333333333333");
 
-        trie.set(key1, b"val1");
-        trie.set(key2, b"val2");
-        trie.set(key3, b"val3");
+        trie.set(key1, b"val1").unwrap();
+        trie.set(key2, b"val2").unwrap();
+        trie.set(key3, b"val3").unwrap();
 
         trie.print_tree();
 
         // Delete key3 should cause branch to collapse and extensions to merge
-        assert!(trie.delete(key3));
+        assert!(trie.delete(key3).unwrap());
 
         trie.print_tree();
 
-        assert_eq!(trie.get(key1), Some(b"val1".to_vec()));
-        assert_eq!(trie.get(key2), Some(b"val2".to_vec()));
-        assert_eq!(trie.get(key3), None);
+        assert_eq!(trie.get(key1).unwrap(), Some(b"val1".to_vec()));
+        assert_eq!(trie.get(key2).unwrap(), Some(b"val2".to_vec()));
+        assert_eq!(trie.get(key3).unwrap(), None);
     }
 
     #[test]
@@ -186,11 +188,11 @@ mod api_tests {
         let key1 = Key32(*b"exists11111111111111111111111111");
         let key2 = Key32(*b"nothere1111111111111111111111111");
 
-        trie.set(key1, b"value");
+        trie.set(key1, b"value").unwrap();
 
         // Should return false for non-existent key
-        assert!(!trie.delete(key2));
-        assert_eq!(trie.get(key1), Some(b"value".to_vec()));
+        assert!(!trie.delete(key2).unwrap());
+        assert_eq!(trie.get(key1).unwrap(), Some(b"value".to_vec()));
     }
 
     #[test]
@@ -198,7 +200,7 @@ mod api_tests {
         let mut trie = Trie::new();
         let key = Key32(*b"anykey11111111111111111111111111");
 
-        assert!(!trie.delete(key));
+        assert!(!trie.delete(key).unwrap());
     }
 
     #[test]
@@ -208,10 +210,10 @@ mod api_tests {
         let key = Key32(*b"123456abcdefghijklmnopqrstuvwxyz");
         let bad_key = Key32(*b"zyxwvutsrqponmlkjihgfedcba123456");
 
-        trie.set(key, b"hello");
+        trie.set(key, b"hello").unwrap();
 
-        assert_eq!(trie.get(key), Some(b"hello".to_vec()));
-        assert_eq!(trie.get(bad_key), None);
+        assert_eq!(trie.get(key).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(trie.get(bad_key).unwrap(), None);
     }
 
     #[test]
@@ -231,12 +233,12 @@ mod api_tests {
 
         // Insert all keys
         for (key, value) in keys.iter().zip(values.iter()) {
-            trie.set(*key, value.to_vec());
+            trie.set(*key, value).unwrap();
         }
 
         // Verify all keys can be retrieved
         for (key, value) in keys.iter().zip(values.iter()) {
-            assert_eq!(trie.get(*key), Some(value.to_vec()));
+            assert_eq!(trie.get(*key).unwrap(), Some(value.to_vec()));
         }
 
         trie.print_tree();
@@ -245,7 +247,7 @@ mod api_tests {
 
         // Verify non-existent key returns None
         let bad_key = Key32(*b"999999abcdefghijklmnopqrstuvwxyz");
-        assert_eq!(trie.get(bad_key), None);
+        assert_eq!(trie.get(bad_key).unwrap(), None);
     }
 
     #[test]
@@ -256,22 +258,22 @@ mod api_tests {
         let key1 = Key32(*b"123456abcdefghijklmnopqrstuvwxyz");
         let key2 = Key32(*b"123456abcdefghijklmnopqrstuvwxya");
 
-        trie.set(key1, b"first");
-        trie.set(key2, b"second");
+        trie.set(key1, b"first").unwrap();
+        trie.set(key2, b"second").unwrap();
 
         // This key should split the extension
         let key3 = Key32(*b"123456abcdefghijblmnopqrstuvwxyz");
-        trie.set(key3, b"third");
+        trie.set(key3, b"third").unwrap();
 
         // All keys should be retrievable
-        assert_eq!(trie.get(key1), Some(b"first".to_vec()));
-        assert_eq!(trie.get(key2), Some(b"second".to_vec()));
-        assert_eq!(trie.get(key3), Some(b"third".to_vec()));
+        assert_eq!(trie.get(key1).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(trie.get(key2).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(trie.get(key3).unwrap(), Some(b"third".to_vec()));
     }
 
     #[test]
     fn complex_trie_operations_with_db() {
-        let mut trie = Trie::with_db("db", "mpt");
+        let mut trie = Trie::from_db(MemoryDB::new());
 
         // This test builds a complex trie structure with branches and extensions
         let keys = [
@@ -286,17 +288,17 @@ mod api_tests {
 
         // Insert all keys
         for (key, value) in keys.iter().zip(values.iter()) {
-            trie.set(*key, value.to_vec());
+            trie.set(*key, value).unwrap();
         }
 
         // Verify all keys can be retrieved
         for (key, value) in keys.iter().zip(values.iter()) {
-            assert_eq!(trie.get(*key), Some(value.to_vec()));
+            assert_eq!(trie.get(*key).unwrap(), Some(value.to_vec()));
         }
 
-        trie.commit();
+        trie.commit().unwrap();
 
-        let value = trie.get(keys[2]);
+        let value = trie.get(keys[2]).unwrap();
         println!(
             "value: {:?}",
             String::from_utf8(value.clone().unwrap()).unwrap()
@@ -306,25 +308,205 @@ mod api_tests {
 
     #[test]
     fn commit_trie_with_db() {
-        let mut trie = Trie::with_db("db", "mpt");
-        let key = String::from("hello").into();
+        let mut trie = Trie::from_db(MemoryDB::new());
+        let key: Key32 = String::from("hello").into();
         println!("key: {:x?}", key);
-        trie.set(key, b"world");
+        trie.set(key, b"world").unwrap();
 
-        trie.commit();
+        trie.commit().unwrap();
 
         println!("\n\n NOW WE GET THE VALUE FROM THE DB \n\n");
 
         //Now we get the value from the db
-        let value = trie.get(key);
+        let value = trie.get(key).unwrap();
         println!("value: {:?}", value);
     }
 
+    #[test]
+    fn variable_length_keys_match_ethereum_roots() {
+        let mut trie = Trie::new();
+        trie.set(b"doe", b"reindeer").unwrap();
+        trie.set(b"dog", b"puppy").unwrap();
+        trie.set(b"dogglesworth", b"cat").unwrap();
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+
+        let mut trie = Trie::new();
+        trie.set(b"do", b"verb").unwrap();
+        trie.set(b"horse", b"stallion").unwrap();
+        trie.set(b"doge", b"coin").unwrap();
+        trie.set(b"dog", b"puppy").unwrap();
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        );
+        assert_eq!(trie.get(b"do").unwrap(), Some(b"verb".to_vec()));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(trie.get(b"d").unwrap(), None);
+        assert_eq!(trie.get(b"dogs").unwrap(), None);
+    }
+
+    #[test]
+    fn prefix_keys_store_value_in_branch() {
+        let mut trie = Trie::new();
+        trie.set(b"ab", b"long").unwrap();
+        trie.set(b"a", b"short").unwrap();
+        trie.set(b"", b"empty").unwrap();
+
+        assert_eq!(trie.get(b"a").unwrap(), Some(b"short".to_vec()));
+        assert_eq!(trie.get(b"ab").unwrap(), Some(b"long".to_vec()));
+        assert_eq!(trie.get(b"").unwrap(), Some(b"empty".to_vec()));
+
+        // Deleting the longer key collapses the branch holding "a" back into a leaf
+        assert!(trie.delete(b"ab").unwrap());
+        assert!(!trie.delete(b"ab").unwrap());
+        assert!(trie.delete(b"").unwrap());
+        assert_eq!(trie.get(b"a").unwrap(), Some(b"short".to_vec()));
+        assert!(matches!(trie.root(), Some(Node::Leaf(_))));
+
+        let mut expected = Trie::new();
+        expected.set(b"a", b"short").unwrap();
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn deletes_restore_structure_of_remaining_keys() {
+        // Short keys over a tiny alphabet, so many keys are prefixes of each other
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|_| {
+                let len = random::<u8>() % 4;
+                (0..len).map(|_| random::<u8>() % 4).collect()
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut trie = Trie::new();
+        for key in &keys {
+            trie.set(key, key).unwrap();
+        }
+
+        let (removed, kept) = keys.split_at(keys.len() / 2);
+        for key in removed {
+            trie.delete(key).unwrap();
+        }
+
+        let mut expected = Trie::new();
+        for key in kept {
+            expected.set(key, key).unwrap();
+        }
+
+        assert_eq!(trie.root(), expected.root());
+        assert_eq!(trie.root_hash(), expected.root_hash());
+        for key in kept {
+            assert_eq!(trie.get(key).unwrap(), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn iter_yields_entries_in_key_order() {
+        let mut trie = Trie::new();
+        let mut expected = BTreeMap::new();
+
+        for _ in 0..300 {
+            let len = random::<u8>() % 5;
+            let key: Vec<u8> = (0..len).map(|_| random::<u8>() % 8).collect();
+            let value = random::<[u8; 4]>().to_vec();
+            trie.set(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+
+        let entries: Vec<_> = trie.iter().map(Result::unwrap).collect();
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+        assert!(Trie::new().iter().next().is_none());
+    }
+
+    #[test]
+    fn range_prefix_and_reverse_iteration() {
+        let mut trie = Trie::new();
+        let mut expected = BTreeMap::new();
+
+        for _ in 0..300 {
+            let len = random::<u8>() % 5;
+            let key: Vec<u8> = (0..len).map(|_| random::<u8>() % 8).collect();
+            // Never empty, an empty value would delete the key
+            let value = [key.as_slice(), b"v"].concat();
+            trie.set(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+
+        let start = vec![2, 5];
+        let end = vec![5, 1, 3];
+        let owned = |(k, v): (&Vec<u8>, &Vec<u8>)| (k.clone(), v.clone());
+
+        assert!(
+            trie.range(start.clone()..end.clone())
+                .map(Result::unwrap)
+                .eq(expected.range(start.clone()..end.clone()).map(owned))
+        );
+        assert!(
+            trie.range(start.clone()..=end.clone())
+                .map(Result::unwrap)
+                .eq(expected.range(start.clone()..=end.clone()).map(owned))
+        );
+        assert!(
+            trie.range_rev(start.clone()..)
+                .map(Result::unwrap)
+                .eq(expected.range(start.clone()..).rev().map(owned))
+        );
+        assert!(
+            trie.iter_rev()
+                .map(Result::unwrap)
+                .eq(expected.iter().rev().map(owned))
+        );
+
+        // "the next 3 keys after X"
+        let after = (Bound::Excluded(start.clone()), Bound::Unbounded);
+        assert!(
+            trie.range(after.clone())
+                .take(3)
+                .map(Result::unwrap)
+                .eq(expected.range(after).take(3).map(owned))
+        );
+
+        // A prefix of three nibbles ends in the middle of the second byte
+        let prefix = NibblePath::new(vec![0, 3, 0]);
+        let under_prefix = expected
+            .iter()
+            .filter(|(k, _)| k.len() >= 2 && k[0] == 3 && k[1] >> 4 == 0)
+            .map(owned);
+        assert!(
+            trie.iter_prefix(&prefix)
+                .map(Result::unwrap)
+                .eq(under_prefix)
+        );
+    }
+
+    #[test]
+    fn missing_nodes_are_errors() {
+        assert!(matches!(Trie::new().commit(), Err(TrieError::NoDb)));
+
+        let root = random::<[u8; 32]>();
+        let mut trie = Trie::open(MemoryDB::new(), root);
+
+        assert!(matches!(
+            trie.get(b"dog"),
+            Err(TrieError::MissingNode { hash, path }) if hash == root && path.is_empty()
+        ));
+        assert!(trie.set(b"dog", b"puppy").is_err());
+        assert!(matches!(
+            trie.iter().next(),
+            Some(Err(TrieError::MissingNode { .. }))
+        ));
+    }
+
     // #[test]
     // fn get_trie_with_db() {
-    //     let trie = Trie::with_db("db", "mpt");
+    //     let trie = Trie::with_db("db", "mpt").unwrap();
     //     let key = String::from("hello").into();
-    //     let value = trie.get(key);
+    //     let value = trie.get(key).unwrap();
     //     assert_eq!(value, Some(b"world".to_vec()));
     // }
 }